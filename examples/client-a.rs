@@ -6,35 +6,58 @@ use std::sync::{mpsc, Arc, Mutex};
 use con::Error;
 use con::Client;
 use con::MsgName;
+use con::Payload;
 
 fn main() -> Result<(), Error> {
     println!(" → Client A");
 
     let ctx = Arc::new(Mutex::new(0u64));
-    let mut client = Client::connect("/tmp/con-examples.sock", ctx.clone(), Some("client-a"))?;
+    let mut client = Client::connect("unix:///tmp/con-examples.sock", ctx.clone(), Some("client-a"))?;
 
     // Send message
     println!(" → Send 'msg-A' with body 'Just body'");
-    client.send("msg-A", Some(Vec::from("Just body...")));
+    let payload = Payload::new(Some(Vec::from("Just body...")))
+        .with_header("content-type", "text/plain");
+    client.send("msg-A", payload)?;
+
+    // Send message to client-b through the server
+    println!(" → Send 'hello' to client-b");
+    client.send_to("client-b", "hello", Some(Vec::from("Hi there!")))?;
 
     // Request
     let (ans_rx, ans_tx) = mpsc::channel();
     println!(" → Request 'repeat' with body 'this'");
-    client.req("repeat", Some(Vec::from("this")), ans_rx);
-    if let Some(ans) = ans_tx.recv().unwrap() {
+    client.req("repeat", Some(Vec::from("this")), ans_rx)?;
+    if let Ok(Some(ans)) = ans_tx.recv().unwrap() {
         println!(" → Repeat result: {:?}", String::from_utf8_lossy(&ans));
     }
 
+    // Request served by client-b
+    let (ans_rx, ans_tx) = mpsc::channel();
+    println!(" → Request 'render' from client-b");
+    client.req("render", None, ans_rx)?;
+    if let Ok(Some(ans)) = ans_tx.recv().unwrap() {
+        println!(" → Render result: {:?}", String::from_utf8_lossy(&ans));
+    }
+
     // Subscribe
     client.on(MsgName::Any, |msg, _state, _ctx| {
+        if msg.err {
+            println!(" → Error for {:?}: {:?}", msg.name, msg.peer);
+            return None;
+        }
         let body = match msg.body {
             Some(b) => String::from_utf8_lossy(&b).to_string(),
             None => "<Nothing>".to_string(),
         };
         println!(" → Got msg form server: {:?}", body);
-    });
+        None
+    })?;
 
     loop {
         thread::sleep(time::Duration::from_millis(1000));
+        if let Some(rtt) = client.rtt() {
+            println!(" → Round-trip time: {:?}", rtt);
+        }
     }
 }
\ No newline at end of file
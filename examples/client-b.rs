@@ -11,11 +11,11 @@ fn main() -> Result<(), Error> {
     println!(" → Client B");
 
     let ctx = Arc::new(Mutex::new(0u64));
-    let mut client = Client::connect("/tmp/con-examples.sock", ctx.clone(), Some("client-b"))?;
+    let mut client = Client::connect("unix:///tmp/con-examples.sock", ctx.clone(), Some("client-b"))?;
 
     // Send messages
-    client.send("msg-A", None);
-    client.send("another msg", Some(Vec::from("with body")));
+    client.send("msg-A", None)?;
+    client.send("another msg", Some(Vec::from("with body")))?;
 
     // Subscribe
 	client.on(MsgName::Is("msg-from-server"), |msg, _state, _ctx| {
@@ -24,7 +24,36 @@ fn main() -> Result<(), Error> {
             None => "<Nothing>".to_string(),
         };
         println!(" → Got msg from server {:?}", body);
-	});
+        None
+	})?;
+
+    // Receive messages of the topic
+    client.join("news")?;
+    client.on(MsgName::Is("headline"), |msg, _state, _ctx| {
+        if let Some(body) = msg.body {
+            println!(" → Got headline {:?}", String::from_utf8_lossy(&body));
+        }
+        None
+    })?;
+
+    // Messages from other clients
+    client.on(MsgName::Is("hello"), |msg, _state, _ctx| {
+        if let Some(body) = msg.body {
+            println!(" → Got hello from {:?}: {:?}", msg.peer, String::from_utf8_lossy(&body));
+        }
+        None
+    })?;
+
+    // Serve 'render' requests of other clients
+    client.provide("render")?;
+    client.on(MsgName::Is("render"), |msg, _state, _ctx| {
+        Some(Vec::from(format!("Rendered for {:?}", msg.peer)))
+    })?;
+
+    // Answer server's requests
+    client.on(MsgName::Is("status"), |_msg, _state, _ctx| {
+        Some(Vec::from("ok"))
+    })?;
 
     loop {
         thread::sleep(time::Duration::from_millis(1000));
@@ -29,8 +29,8 @@ fn main() -> Result<(), Error> {
 
         // Make request
         let (ans_rx, ans_tx) = mpsc::channel();
-        client.req("repeat", Some(Vec::from("this")), ans_rx);
-        if let Some(_) = ans_tx.recv().unwrap() {
+        client.req("repeat", Some(Vec::from("this")), ans_rx)?;
+        if let Ok(Some(_)) = ans_tx.recv().unwrap() {
             // println!(" → Repeat result: {:?}", String::from_utf8_lossy(&ans));
         }
     }
@@ -1,17 +1,34 @@
 extern crate con;
+extern crate tracing_subscriber;
 
 use con::ClientName;
 use con::Error;
+use con::{Flow, Layer, Msg};
 use con::MsgName;
 use con::Server;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time;
 
+/// Reject requests without body before they reach handlers
+struct RequireBody;
+
+impl Layer<u64> for RequireBody {
+    fn before(&self, msg: Msg, _state: &con::server::SharedState<u64>, _ctx: &Arc<Mutex<u64>>) -> Flow {
+        if msg.req && msg.body.is_none() && msg.name == "repeat" {
+            return Flow::Reject("Nothing to repeat.".to_string());
+        }
+        Flow::Next(msg)
+    }
+}
+
 fn main() -> Result<(), Error> {
+    tracing_subscriber::fmt::init();
+
     let ctx = Arc::new(Mutex::new(0u64));
     let mut server = Server::new(ctx.clone());
     let server_state = server.state.clone();
+    server.layer(RequireBody)?;
 
     // Subscribe: all clients - msg 'msg-A'
     server.on(ClientName::Any, MsgName::Is("msg-A"), |msg, state, ctx| {
@@ -23,6 +40,12 @@ fn main() -> Result<(), Error> {
         };
 
         println!(" → {:?} Client: {:?}, msg: 'msg-A'", *ctx, client_name);
+        if let Some(client) = state.client(&msg.client) {
+            println!("      from: {:?}", client.peer);
+        }
+        if let Some(content_type) = msg.header("content-type") {
+            println!("      content type: {:?}", content_type);
+        }
         if let Some(body) = msg.body {
             println!("      with body: {:?}", String::from_utf8_lossy(&body));
         }
@@ -66,6 +89,16 @@ fn main() -> Result<(), Error> {
         },
     )?;
 
+    // Subscribe: clients with 'client-' prefix - msgs matching 'another*'
+    server.on(
+        ClientName::Prefix("client-"),
+        MsgName::Glob("another*"),
+        |msg, _state, _ctx| {
+            println!(" → Client with prefix 'client-', msg: {:?}", msg.name);
+            None
+        },
+    )?;
+
     // Handle request
     server.on(
         ClientName::Is("client-a"),
@@ -83,18 +116,23 @@ fn main() -> Result<(), Error> {
         },
     )?;
 
-    server.on(
-        ClientName::Any,
-        MsgName::Is("disconnect"),
-        |_msg, _state, _ctx| {
-            println!(" → Disconnect!");
-            None
-        },
-    )?;
+    server.on_handshake(|client, _ctx| {
+        println!(" → Handshake: {:?} {:?}", client.id, client.name);
+    })?;
+
+    server.on_disconnect(|client, reason, _ctx| {
+        println!(" → Disconnect: {:?} {:?}, reason: {:?}", client.id, client.name, reason);
+    })?;
+
+    // Serve metrics for Prometheus
+    let metrics_state = server.state.clone();
+    thread::spawn(move || {
+        Server::serve_metrics(&metrics_state, "127.0.0.1:9464").unwrap();
+    });
 
     // Start listening in another thread
     thread::spawn(move || {
-        server.listen("/tmp/con-examples.sock").unwrap();
+        server.listen("unix:///tmp/con-examples.sock").unwrap();
     });
 
     loop {
@@ -107,7 +145,7 @@ fn main() -> Result<(), Error> {
         )?;
 
         // Send message to 'client-a'
-        con::Server::send(
+        let sent = con::Server::send(
             &server_state,
             "client-a",
             "some-msg",
@@ -115,6 +153,17 @@ fn main() -> Result<(), Error> {
                 "This is special message for client-A: {}",
                 ctx
             ))),
+        );
+        if let Err(err) = sent {
+            println!(" → Cannot send to client-a: {:?}", err);
+        }
+
+        // Send message to clients joined the 'news' topic
+        con::Server::publish(
+            &server_state,
+            "news",
+            "headline",
+            Some(Vec::from(format!("News #{}", ctx))),
         )?;
 
         if *ctx == 10 {
@@ -124,6 +173,12 @@ fn main() -> Result<(), Error> {
 
         *ctx += 1;
         drop(ctx);
+
+        // Ask 'client-b' for its status
+        if let Ok(Some(status)) = con::Server::req(&server_state, "client-b", "status", None) {
+            println!(" → Status of client-b: {:?}", String::from_utf8_lossy(&status));
+        }
+
         thread::sleep(time::Duration::from_millis(500));
     }
 }
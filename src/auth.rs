@@ -0,0 +1,136 @@
+use errors::Error;
+use hmac::{Hmac, Mac};
+use peer::PeerCred;
+use rand::{OsRng, RngCore};
+use sha2::Sha256;
+
+/// How the server authenticates clients in the handshake.
+#[derive(Clone)]
+pub enum Auth {
+    /// Accept everyone.
+    None,
+    /// Client must present this token.
+    Token(String),
+    /// Client must sign the server's challenge with this secret (HMAC-SHA256).
+    Hmac(Vec<u8>),
+    /// Client's name and credential are checked by the function.
+    Custom(fn(name: &str, credential: &[u8]) -> bool),
+}
+
+/// Unix socket peer check, called before the client is accepted.
+pub type Authorizer = fn(&PeerCred) -> bool;
+
+/// Credentials presented by the client in the handshake.
+#[derive(Debug, Clone, Default)]
+pub enum Credentials {
+    #[default]
+    None,
+    /// Shared token (for `Auth::Token` or `Auth::Custom`).
+    Token(String),
+    /// Shared secret used to sign the server's challenge.
+    Hmac(Vec<u8>),
+}
+
+impl Auth {
+    /// Check client's credential. `challenge` is the one sent to this client.
+    pub fn verify(&self, name: &str, credential: &[u8], challenge: &[u8]) -> bool {
+        match self {
+            Auth::None => true,
+            Auth::Token(token) => eq(token.as_bytes(), credential),
+            Auth::Hmac(secret) => {
+                let mut mac = match Hmac::<Sha256>::new_from_slice(secret) {
+                    Ok(mac) => mac,
+                    Err(_) => return false,
+                };
+                mac.update(challenge);
+                mac.verify_slice(credential).is_ok()
+            }
+            Auth::Custom(check) => check(name, credential),
+        }
+    }
+
+    /// Check if the client must be challenged before the handshake.
+    pub fn needs_challenge(&self) -> bool {
+        matches!(self, Auth::Hmac(_))
+    }
+}
+
+impl ::std::fmt::Debug for Auth {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match self {
+            Auth::None => write!(f, "Auth::None"),
+            Auth::Token(_) => write!(f, "Auth::Token(..)"),
+            Auth::Hmac(_) => write!(f, "Auth::Hmac(..)"),
+            Auth::Custom(_) => write!(f, "Auth::Custom(..)"),
+        }
+    }
+}
+
+impl Credentials {
+    /// Make the credential sent in the handshake.
+    pub fn credential(&self, challenge: &[u8]) -> Vec<u8> {
+        match self {
+            Credentials::None => Vec::new(),
+            Credentials::Token(token) => Vec::from(token.as_bytes()),
+            Credentials::Hmac(secret) => sign(secret, challenge),
+        }
+    }
+}
+
+/// Generate random challenge, fails if OS random source is unavailable.
+pub fn challenge() -> Result<Vec<u8>, Error> {
+    let mut bytes = vec![0u8; 32];
+    OsRng::new()
+        .and_then(|mut rng| rng.try_fill_bytes(&mut bytes))
+        .map_err(|err| Error::Handshake(format!("Cannot generate challenge: {}", err)))?;
+    Ok(bytes)
+}
+
+/// Sign the challenge with the secret (HMAC-SHA256).
+pub fn sign(secret: &[u8], challenge: &[u8]) -> Vec<u8> {
+    match Hmac::<Sha256>::new_from_slice(secret) {
+        Ok(mut mac) => {
+            mac.update(challenge);
+            mac.finalize().into_bytes().to_vec()
+        }
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Compare in constant time.
+fn eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// -----------------------------
+// --- --- --- Tests --- --- ---
+// -----------------------------
+#[cfg(test)]
+mod tests {
+    use auth::*;
+
+    #[test]
+    fn token() {
+        let auth = Auth::Token("secret".to_string());
+        let cred = Credentials::Token("secret".to_string()).credential(&[]);
+        assert!(auth.verify("cli", &cred, &[]));
+        assert!(!auth.verify("cli", b"secreT", &[]));
+        assert!(!Auth::Token(String::new()).verify("cli", b"x", &[]));
+    }
+
+    #[test]
+    fn hmac() {
+        let auth = Auth::Hmac(Vec::from("secret"));
+        let ch = challenge().unwrap();
+        assert_eq!(ch.len(), 32);
+        assert_ne!(ch, vec![0u8; 32]);
+        let cred = Credentials::Hmac(Vec::from("secret")).credential(&ch);
+        assert!(auth.verify("cli", &cred, &ch));
+        assert!(!auth.verify("cli", &cred, &challenge().unwrap()));
+        let wrong = Credentials::Hmac(Vec::from("other")).credential(&ch);
+        assert!(!auth.verify("cli", &wrong, &ch));
+    }
+}
@@ -1,30 +1,66 @@
+use auth::Credentials;
+use endpoint::Endpoint;
 use errors::Error;
+use heartbeat::{self, Heartbeat};
+use std::io::Write;
 use std::thread;
-use std::net::{TcpStream, Shutdown};
-use std::os::unix::net::UnixStream;
+use std::net::Shutdown;
 use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
 use stream::ConStream;
-use message::{Msg, MsgName, MsgReading, MSG_REQ, MSG_WITH_BODY};
+#[cfg(feature = "tls")]
+use tls::TlsStream;
+use trace::TraceCtx;
+use tracing::field;
+use message::{Msg, MsgName, MsgReading, Payload, MSG_REQ, MSG_WITH_BODY, RESERVED_PREFIX};
+use pattern::Pattern;
 use utils;
 
-pub type HandlerFunc<T> = fn(msg: Msg, state: SharedState<T>, ctx: Arc<Mutex<T>>);
+pub type HandlerFunc<T> = fn(msg: Msg, state: SharedState<T>, ctx: Arc<Mutex<T>>) -> OptBody;
 pub type SharedState<T> = Arc<Mutex<State<T>>>;
 pub type OptBody = Option<Vec<u8>>;
 
 #[derive(Debug)]
 pub struct Handler<T> {
     func: Option<HandlerFunc<T>>,
-    ans: Option<mpsc::Sender<OptBody>>,
+    ans: Option<mpsc::Sender<Result<OptBody, Error>>>,
     once: bool,
     called: bool,
     msg_id: Option<u128>,
-    msg_name: Option<String>,
+    msg_name: Pattern,
     client_id: Option<String>,
 }
 
 #[derive(Debug)]
 pub struct State<T> {
     pub handlers: Vec<Handler<T>>,
+    pub heartbeat: Heartbeat,
+}
+
+/// Client settings.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Credentials presented in the handshake.
+    pub credentials: Credentials,
+    /// How often to ping the server.
+    pub ping_interval: Option<Duration>,
+    /// Disconnect if the server sent nothing (not even pong) for this long.
+    pub idle_timeout: Option<Duration>,
+    /// Trusted certificates and identity for "tls://" addresses, see `tls::client_config`.
+    #[cfg(feature = "tls")]
+    pub tls: Option<Arc<rustls::ClientConfig>>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            credentials: Credentials::None,
+            ping_interval: Some(Duration::from_secs(15)),
+            idle_timeout: Some(Duration::from_secs(45)),
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -32,23 +68,36 @@ pub struct Client<T> {
     pub state: Arc<Mutex<State<T>>>,
     pub ctx: Arc<Mutex<T>>,
     id: Option<String>,
-    stream: ConStream,
+    name: Option<String>,
+    stream: Arc<Mutex<ConStream>>,
 }
 
 impl<T: Sync + Send + 'static> Client<T> {
-    /// Connect to server
+    /// Connect to server, see `Endpoint` for supported address formats
     pub fn connect(address: &str, ctx: T, name: Option<&str>) -> Result<Client<T>, Error> {
+        Client::connect_with(address, ctx, name, Config::default())
+    }
+
+    /// Connect to server with given settings
+    pub fn connect_with(
+        address: &str,
+        ctx: T,
+        name: Option<&str>,
+        config: Config,
+    ) -> Result<Client<T>, Error> {
         // Connect
-        let stream = if address.starts_with("/") && address.ends_with(".sock") {
-            let stream = UnixStream::connect(address)?;
-            ConStream::new_unix(stream)
-        } else {
-            let stream = TcpStream::connect(address)?;
-            ConStream::new_tcp(stream)
+        let stream = match Endpoint::parse(address)? {
+            #[cfg(feature = "tls")]
+            Endpoint::Tls(ref addr) => match config.tls {
+                Some(ref tls) => ConStream::new_tls(TlsStream::connect(addr, tls.clone())?),
+                None => return Err(Error::Tls(format!("No TLS settings to connect {}", address))),
+            },
+            endpoint => endpoint.connect()?,
         };
 
         let state = State {
             handlers: Vec::with_capacity(5),
+            heartbeat: Heartbeat::new(),
         };
 
         let mut cloned_stream = stream.try_clone()?;
@@ -56,78 +105,212 @@ impl<T: Sync + Send + 'static> Client<T> {
             state: Arc::new(Mutex::new(state)),
             ctx: Arc::new(Mutex::new(ctx)),
             id: None,
-            stream: stream,
+            name: None,
+            stream: Arc::new(Mutex::new(stream)),
         };
-        instance.handshake(&mut cloned_stream, name);
+        if let Err(err) = instance.handshake(&mut cloned_stream, name, &config.credentials) {
+            warn!(%address, error = ?err, "Handshake failed");
+            return Err(err);
+        }
+        info!(%address, client_id = ?instance.id, client_name = ?instance.name, "Connected");
 
         let mux_state = instance.state.clone();
         let mux_ctx = instance.ctx.clone();
+        let mux_stream = instance.stream.clone();
+        let mux_id = instance.id.clone();
         thread::spawn(move || {
-            Msg::read(&mut cloned_stream, |msg| {
-                let msg = match Client::<T>::parse_msg(msg) {
+            let mut dispatch = |msg: &[u8]| {
+                let msg = match Msg::from_bytes(msg, "") {
                     Ok(msg) => msg,
-                    Err(_) => return MsgReading::Stop,
+                    Err(err) => {
+                        warn!(client_id = ?mux_id, error = ?err, "Malformed message");
+                        return MsgReading::Stop;
+                    }
                 };
-
-                let msg_id = msg.id;
-                let msg_name = msg.name.clone();
-                let msg_body = msg.body.clone();
                 let mut state = mux_state.lock().unwrap();
-                for h in  state.handlers.iter_mut() {
-                    let mut matched = true;
+
+                // Heartbeats
+                state.heartbeat.seen();
+                if msg.name == "$ping" {
+                    let pong = Msg::raw(msg.id, 0, "$pong", None);
+                    let mut stream = mux_stream.lock().unwrap();
+                    if let Err(err) = stream.write_all(&pong).and_then(|_| stream.flush()) {
+                        warn!(client_id = ?mux_id, error = ?err, "Cannot answer ping");
+                        return MsgReading::Stop;
+                    }
+                    return MsgReading::Continue;
+                }
+                if msg.name == "$pong" {
+                    state.heartbeat.pong(msg.id);
+                    return MsgReading::Continue;
+                }
+
+                // Request is answered once, with the first body returned by handlers
+                let mut answer: Option<OptBody> = None;
+                for h in state.handlers.iter_mut() {
+                    let mut matched = h.msg_name.matches(&msg.name);
                     if let Some(ref id) = h.msg_id {
-                        matched = matched && *id == msg_id;
+                        matched = matched && *id == msg.id;
+                    }
+                    if !matched || (h.once && h.called) {
+                        continue;
                     }
-                    if let Some(ref name) = h.msg_name {
-                        matched = matched && *name == msg_name;
+                    h.called = true;
+
+                    if let Some(ref mut ans) = h.ans {
+                        let body = msg.body.clone();
+                        let ans_body = match msg.err {
+                            true => Err(Error::Remote(String::from_utf8_lossy(&body.unwrap_or_default()).to_string())),
+                            false => Ok(body),
+                        };
+                        ans.send(ans_body).unwrap_or(());
                     }
-                    if matched {
-                        if h.once {
-                            if !h.called {
-                                if let Some(ref mut ans) = h.ans {
-                                    ans.send(msg_body.clone()).unwrap();
-                                }
-                                if let Some(f) = h.func {
-                                    f(msg.clone(), mux_state.clone(), mux_ctx.clone());
-                                }
-                                h.called = true
-                            }
-                        } else {
-                            if let Some(ref mut ans) = h.ans {
-                                ans.send(msg_body.clone()).unwrap();
-                            }
-                            if let Some(f) = h.func {
-                                f(msg.clone(), mux_state.clone(), mux_ctx.clone());
-                            }
+                    if let Some(f) = h.func {
+                        // Messages sent by the handler continue the trace
+                        let trace = msg.trace.map(|t| t.child());
+                        let _trace_guard = trace.map(TraceCtx::enter);
+                        let span = info_span!(
+                            "handler",
+                            msg_id = msg.id,
+                            msg_name = %msg.name,
+                            trace_id = field::Empty,
+                            span_id = field::Empty,
+                            parent_id = field::Empty,
+                        );
+                        if let Some(trace) = trace {
+                            trace.record(&span);
                         }
+                        let _span_guard = span.enter();
+                        let ans = f(msg.clone(), mux_state.clone(), mux_ctx.clone());
+                        if msg.req && answer.as_ref().is_none_or(|a| a.is_none()) {
+                            answer = Some(ans);
+                        }
+                    }
+                }
+                state.handlers.retain(|h| !(h.once && h.called));
+
+                // Answer server's request, the connection is gone if it fails
+                if let Some(ans) = answer {
+                    let meta = if ans.is_none() { 0 } else { MSG_WITH_BODY };
+                    let frame = Msg::raw(msg.id, meta, &msg.name, ans);
+                    let mut stream = mux_stream.lock().unwrap();
+                    if let Err(err) = stream.write_all(&frame).and_then(|_| stream.flush()) {
+                        warn!(client_id = ?mux_id, msg_name = %msg.name, error = ?err, "Cannot answer request");
+                        return MsgReading::Stop;
                     }
                 }
 
                 MsgReading::Continue
-            });
+            };
+            Msg::read(&mut cloned_stream, &mut dispatch);
+            info!(client_id = ?mux_id, "Disconnected");
+
+            // Let handlers know the connection is gone
+            dispatch(&Msg::raw(0, 0, "$disconnect", None));
+        });
+
+        // Ping server and disconnect if it's gone
+        let hb_state = Arc::downgrade(&instance.state);
+        let hb_stream = instance.stream.clone();
+        let (ping_interval, idle_timeout) = (config.ping_interval, config.idle_timeout);
+        thread::spawn(move || loop {
+            thread::sleep(heartbeat::tick(ping_interval, idle_timeout));
+            let state = match hb_state.upgrade() {
+                Some(s) => s,
+                None => return,
+            };
+            let mut state = state.lock().unwrap();
+            let mut stream = hb_stream.lock().unwrap();
+            if state.heartbeat.is_idle(idle_timeout) {
+                warn!(idle_timeout = ?idle_timeout, "Server is not responding, disconnecting");
+                let _ = stream.shutdown(Shutdown::Both);
+                return;
+            }
+            if let Some(id) = state.heartbeat.ping(ping_interval) {
+                let ping = Msg::raw(id, 0, "$ping", None);
+                if stream.write_all(&ping).and_then(|_| stream.flush()).is_err() {
+                    return;
+                }
+            }
         });
 
         Ok(instance)
     }
 
-    /// Send message to server
-    pub fn send(&mut self, name: &str, body: OptBody) {
-        let id = utils::bid();
-        let meta = match body {
-            Some(_) => MSG_WITH_BODY,
-            None => 0,
-        };
+    /// Send message to server, names starting with `$` are reserved.
+    pub fn send(&mut self, name: &str, body: impl Into<Payload>) -> Result<(), Error> {
+        if Msg::is_reserved(name) {
+            return Err(Error::Reserved(name.to_string()));
+        }
+        let id = utils::bid_to_u128(&utils::bid());
+        let msg = Msg::new("", id, 0, name)
+            .with_trace(TraceCtx::next())
+            .with_payload(body.into());
 
-        Msg::write(&mut self.stream, &id, &[meta], name.as_bytes(), &body);
+        self.write(&msg)
+    }
+
+    /// Send message to another client (by name or id) through the server.
+    /// If the client is not connected the server answers with an error message.
+    pub fn send_to(&mut self, peer: &str, name: &str, body: impl Into<Payload>) -> Result<(), Error> {
+        if Msg::is_reserved(name) {
+            return Err(Error::Reserved(name.to_string()));
+        }
+        let id = utils::bid_to_u128(&utils::bid());
+        let msg = Msg::new("", id, 0, name)
+            .with_peer(peer)
+            .with_trace(TraceCtx::next())
+            .with_payload(body.into());
+
+        self.write(&msg)
     }
 
     /// Send request to server
-    pub fn req(&mut self, name: &str, body: OptBody, ans: mpsc::Sender<OptBody>) {
-        let id = utils::bid();
-        let meta = match body {
-            Some(_) => MSG_REQ | MSG_WITH_BODY,
-            None => MSG_REQ,
-        };
+    pub fn req(
+        &mut self,
+        name: &str,
+        body: impl Into<Payload>,
+        ans: mpsc::Sender<Result<OptBody, Error>>,
+    ) -> Result<(), Error> {
+        if Msg::is_reserved(name) {
+            return Err(Error::Reserved(name.to_string()));
+        }
+        self.request(name, body.into(), ans)
+    }
+
+    /// Ask the server about "clients", "handlers", "stats" or "info".
+    /// The answer is JSON, or error if the server doesn't allow it.
+    pub fn inspect(&mut self, what: &str, ans: mpsc::Sender<Result<OptBody, Error>>) -> Result<(), Error> {
+        self.request(&format!("{}{}", RESERVED_PREFIX, what), Payload::default(), ans)
+    }
+
+    /// Join the topic to receive messages published to it.
+    pub fn join(&mut self, topic: &str) -> Result<(), Error> {
+        self.control("$join", topic)
+    }
+
+    /// Leave the topic.
+    pub fn leave(&mut self, topic: &str) -> Result<(), Error> {
+        self.control("$leave", topic)
+    }
+
+    /// Serve requests with this name sent by other clients.
+    /// Requests are answered by handlers registered with `on`/`once`.
+    pub fn provide(&mut self, name: &str) -> Result<(), Error> {
+        self.control("$provide", name)
+    }
+
+    /// Send request and pass the answer to the channel.
+    fn request(
+        &mut self,
+        name: &str,
+        payload: Payload,
+        ans: mpsc::Sender<Result<OptBody, Error>>,
+    ) -> Result<(), Error> {
+        let id = utils::bid_to_u128(&utils::bid());
+        let msg = Msg::new("", id, MSG_REQ, name)
+            .with_trace(TraceCtx::next())
+            .with_payload(payload);
 
         let mut state = self.state.lock().unwrap();
         state.handlers.push(Handler {
@@ -135,22 +318,25 @@ impl<T: Sync + Send + 'static> Client<T> {
             ans: Some(ans.clone()),
             once: true,
             called: false,
-            msg_id: Some(utils::bid_to_u128(&id)),
-            msg_name: Some(name.to_string()),
+            msg_id: Some(id),
+            msg_name: Pattern::Is(name.to_string()),
             client_id: None,
         });
         drop(state);
 
-        Msg::write(&mut self.stream, &id, &[meta], name.as_bytes(), &body);
+        self.write(&msg)
     }
 
-    /// Subscribe.
-    pub fn on(&mut self, msg_name: MsgName, func: HandlerFunc<T>) {
+    /// Send control message with the argument.
+    fn control(&mut self, name: &str, arg: &str) -> Result<(), Error> {
+        let id = utils::bid_to_u128(&utils::bid());
+        self.write(&Msg::new("", id, 0, name).with_str_body(arg))
+    }
+
+    /// Subscribe. `$disconnect` is dispatched when the connection ends.
+    pub fn on(&mut self, msg_name: MsgName, func: HandlerFunc<T>) -> Result<(), Error> {
+        let msg_name = msg_name.to_pattern()?;
         let mut state = self.state.lock().unwrap();
-        let msg_name = match msg_name {
-            MsgName::Is(name) => Some(name.to_string()),
-            MsgName::Any => None,
-        };
 
         state.handlers.push(Handler {
             func: Some(func),
@@ -158,19 +344,16 @@ impl<T: Sync + Send + 'static> Client<T> {
             once: false,
             called: false,
             msg_id: None,
-            msg_name: msg_name,
+            msg_name,
             client_id: None,
         });
-        drop(state);
+        Ok(())
     }
 
     /// Subscribe on next message.
-    pub fn once(&mut self, msg_name: MsgName, func: HandlerFunc<T>) {
+    pub fn once(&mut self, msg_name: MsgName, func: HandlerFunc<T>) -> Result<(), Error> {
+        let msg_name = msg_name.to_pattern()?;
         let mut state = self.state.lock().unwrap();
-        let msg_name = match msg_name {
-            MsgName::Is(name) => Some(name.to_string()),
-            MsgName::Any => None,
-        };
 
         state.handlers.push(Handler {
             func: Some(func),
@@ -178,66 +361,122 @@ impl<T: Sync + Send + 'static> Client<T> {
             once: true,
             called: false,
             msg_id: None,
-            msg_name: msg_name,
+            msg_name,
             client_id: None,
         });
-        drop(state);
+        Ok(())
+    }
+
+    /// Round-trip time to the server measured by the last ping.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.state.lock().unwrap().heartbeat.rtt
+    }
+
+    /// Id given by the server.
+    pub fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    /// Name given by the server, may differ from the requested one.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
     }
 
     /// Try to disconnect from server.
     pub fn disconnect(&mut self) -> Result<(), Error> {
         self.id = None;
-        self.stream.shutdown(Shutdown::Both)?;
+        match self.stream.lock() {
+            Ok(stream) => stream.shutdown(Shutdown::Both)?,
+            Err(_) => return Err(Error::Mutex),
+        };
         Ok(())
     }
 
-    /// Handshake with server. Will block thread.
-    fn handshake(&mut self, stream: &mut ConStream, name: Option<&str>) {
-        let id = utils::bid();
-        let name_bin = "handshake".as_bytes();
-        let body = match name {
-            Some(name) => Some(Vec::from(name)),
-            None => None,
+    /// Write message to server, fails if its names don't fit the frame.
+    fn write(&self, msg: &Msg) -> Result<(), Error> {
+        Msg::check_name(&msg.name)?;
+        if let Some(ref peer) = msg.peer {
+            Msg::check_name(peer)?;
+        }
+        let mut stream = match self.stream.lock() {
+            Ok(s) => s,
+            Err(_) => return Err(Error::Mutex),
         };
+        stream.write_all(&msg.to_bytes())?;
+        stream.flush()?;
+        Ok(())
+    }
 
-        Msg::write(stream, &id, &[MSG_REQ | MSG_WITH_BODY], name_bin, &body);
+    /// Handshake with server. Will block thread.
+    fn handshake(
+        &mut self,
+        stream: &mut ConStream,
+        name: Option<&str>,
+        credentials: &Credentials,
+    ) -> Result<(), Error> {
+        // Wait for the challenge to sign, server may reject connection instead
+        let mut challenge = Vec::new();
+        let mut rejected = None;
+        if let Credentials::Hmac(_) = credentials {
+            Msg::read(stream, |msg| {
+                let msg = match Msg::from_bytes(msg, "") {
+                    Ok(msg) => msg,
+                    Err(_) => return MsgReading::Stop,
+                };
+                if msg.name == "$handshake" && msg.err {
+                    rejected = Some(String::from_utf8_lossy(&msg.body.unwrap_or_default()).to_string());
+                    return MsgReading::Stop;
+                }
+                if msg.name != "$challenge" { return MsgReading::Continue; }
+                challenge = msg.body.unwrap_or_default();
+                MsgReading::Stop
+            });
+        }
+        if let Some(reason) = rejected {
+            return Err(Error::Handshake(reason));
+        }
+
+        // Rejected connection may be closed before the request is written,
+        // the answer is read anyway
+        let id = utils::bid_to_u128(&utils::bid());
+        let credential = credentials.credential(&challenge);
+        let body = utils::pack(&[name.unwrap_or("").as_bytes(), &credential]);
+        let msg = Msg::new("", id, MSG_REQ, "$handshake").with_body(&body);
+        let written = stream.write_all(&msg.to_bytes());
+
+        let mut result = None;
         Msg::read(stream, |msg| {
-            if let Ok(msg) = Client::<T>::parse_msg(msg) {
-                // Skip non-handshake response
-                if msg.name != "handshake" { return MsgReading::Continue; }
+            let msg = match Msg::from_bytes(msg, "") {
+                Ok(msg) => msg,
+                Err(err) => {
+                    result = Some(Err(err));
+                    return MsgReading::Stop;
+                }
+            };
 
-                self.id = match msg.body {
-                    Some(b) => Some(String::from_utf8_lossy(&b).to_string()),
-                    None => None,
-                };
+            // Skip non-handshake response
+            if msg.name != "$handshake" { return MsgReading::Continue; }
+
+            let body = msg.body.unwrap_or_default();
+            if msg.err {
+                let reason = String::from_utf8_lossy(&body).to_string();
+                result = Some(Err(Error::Handshake(reason)));
+                return MsgReading::Stop;
             }
+
+            let mut fields = utils::unpack(&body).into_iter();
+            self.id = fields.next().map(|id| String::from_utf8_lossy(&id).to_string());
+            self.name = fields
+                .next()
+                .map(|name| String::from_utf8_lossy(&name).to_string())
+                .filter(|name| !name.is_empty());
+            result = Some(Ok(()));
             MsgReading::Stop
         });
-    }
-
-    /// Parse binary clice to message.
-    fn parse_msg(msg: &[u8]) -> Result<Msg, Error> {
-        // Id, meta, name
-        let id: u128 = utils::bid_to_u128(&msg[0..12]);
-        let name_end: usize = msg[13] as usize + 14;
-        let name = String::from_utf8_lossy(&msg[14..name_end]).to_string();
-
-        // Body
-        let mut body: Option<Vec<u8>> = None;
-        if msg.len() > name_end {
-            let body_len = utils::bytes_to_u64(&msg[name_end..name_end + 8]) as usize;
-            let body_start = name_end + 8;
-            let body_end = body_start + body_len;
-            body = Some(Vec::from(&msg[body_start..body_end]));
+        match (result, written) {
+            (Some(result), _) => result,
+            (None, Err(err)) => Err(Error::IO(err)),
+            (None, Ok(_)) => Err(Error::Handshake("No answer from server.".to_string())),
         }
-
-        Ok(Msg {
-            client: "".to_string(),
-            id: id,
-            req: false,
-            name: name,
-            body: body,
-            ans_tx: None,
-        })
     }
 }
@@ -0,0 +1,210 @@
+use errors::Error;
+use mem::{MemListener, MemStream};
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::str::FromStr;
+use stream::ConStream;
+
+/// Parsed address of the server.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Endpoint {
+    /// Unix socket at the path: `unix:///run/app/bus`.
+    Unix(PathBuf),
+    /// Unix socket in the abstract namespace (Linux only): `unix-abstract:name`.
+    UnixAbstract(String),
+    /// TCP address resolved to any ip version: `tcp://host:port`.
+    Tcp(String),
+    /// TCP address resolved to IPv6 only: `tcp6://[::1]:port`.
+    Tcp6(String),
+    /// TLS over TCP (needs "tls" feature): `tls://host:port`.
+    Tls(String),
+    /// WebSocket upgrade at the path (server only): `ws://host:port/path`.
+    Ws(String, String),
+    /// In-process listener by name: `mem://name`.
+    Mem(String),
+}
+
+impl Endpoint {
+    /// Parse the address with scheme. Addresses without scheme are unix sockets
+    /// if they look like "/path/to.sock", otherwise tcp addresses.
+    pub fn parse(address: &str) -> Result<Endpoint, Error> {
+        let invalid = |reason: &str| Err(Error::Endpoint(format!("{}: {:?}", reason, address)));
+
+        let endpoint = if let Some(path) = address.strip_prefix("unix://") {
+            Endpoint::Unix(PathBuf::from(path))
+        } else if let Some(name) = address.strip_prefix("unix-abstract:") {
+            Endpoint::UnixAbstract(name.to_string())
+        } else if let Some(addr) = address.strip_prefix("tcp://") {
+            Endpoint::Tcp(addr.to_string())
+        } else if let Some(addr) = address.strip_prefix("tcp6://") {
+            if !addr.starts_with('[') {
+                return invalid("IPv6 address must be in brackets");
+            }
+            Endpoint::Tcp6(addr.to_string())
+        } else if let Some(addr) = address.strip_prefix("tls://") {
+            Endpoint::Tls(addr.to_string())
+        } else if let Some(rest) = address.strip_prefix("ws://") {
+            match rest.find('/') {
+                Some(i) => Endpoint::Ws(rest[..i].to_string(), rest[i..].to_string()),
+                None => Endpoint::Ws(rest.to_string(), "/".to_string()),
+            }
+        } else if let Some(name) = address.strip_prefix("mem://") {
+            Endpoint::Mem(name.to_string())
+        } else if address.contains("://") {
+            return invalid("Unknown scheme");
+        } else if address.starts_with('/') && address.ends_with(".sock") {
+            Endpoint::Unix(PathBuf::from(address))
+        } else {
+            Endpoint::Tcp(address.to_string())
+        };
+
+        match endpoint {
+            Endpoint::Unix(ref path) if path.as_os_str().is_empty() => invalid("Empty socket path"),
+            Endpoint::UnixAbstract(ref name) | Endpoint::Mem(ref name) if name.is_empty() => invalid("Empty name"),
+            Endpoint::Tcp(ref addr) | Endpoint::Tcp6(ref addr) | Endpoint::Tls(ref addr) | Endpoint::Ws(ref addr, _)
+                if !has_port(addr) =>
+            {
+                invalid("Missing port")
+            }
+            endpoint => Ok(endpoint),
+        }
+    }
+
+    /// Connect to the endpoint (except TLS that needs settings and WebSocket).
+    pub fn connect(&self) -> Result<ConStream, Error> {
+        Ok(match self {
+            Endpoint::Unix(path) => ConStream::new_unix(UnixStream::connect(path)?),
+            Endpoint::UnixAbstract(name) => ConStream::new_unix(UnixStream::connect_addr(&abstract_addr(name)?)?),
+            Endpoint::Tcp(addr) => ConStream::new_tcp(TcpStream::connect(addr.as_str())?),
+            Endpoint::Tcp6(addr) => ConStream::new_tcp(TcpStream::connect(&resolve_v6(addr)?[..])?),
+            Endpoint::Tls(_) => return Err(Error::Tls(format!("TLS settings are needed to connect {}", self))),
+            Endpoint::Ws(..) => return Err(Error::WebSocket(format!("Cannot connect {}, listen only", self))),
+            Endpoint::Mem(name) => ConStream::new_mem(MemStream::connect(name)?),
+        })
+    }
+
+    /// Bind tcp listener (for tcp endpoints).
+    pub fn bind_tcp(&self) -> Result<TcpListener, Error> {
+        match self {
+            Endpoint::Tcp(addr) | Endpoint::Tls(addr) | Endpoint::Ws(addr, _) => Ok(TcpListener::bind(addr.as_str())?),
+            Endpoint::Tcp6(addr) => Ok(TcpListener::bind(&resolve_v6(addr)?[..])?),
+            _ => Err(Error::Endpoint(format!("Not a tcp endpoint: {}", self))),
+        }
+    }
+
+    /// Bind in-process listener (for memory endpoints).
+    pub fn bind_mem(&self) -> Result<MemListener, Error> {
+        match self {
+            Endpoint::Mem(name) => Ok(MemListener::bind(name)?),
+            _ => Err(Error::Endpoint(format!("Not a memory endpoint: {}", self))),
+        }
+    }
+
+    /// Bind abstract unix listener (for abstract endpoints).
+    pub fn bind_abstract(&self) -> Result<UnixListener, Error> {
+        match self {
+            Endpoint::UnixAbstract(name) => Ok(UnixListener::bind_addr(&abstract_addr(name)?)?),
+            _ => Err(Error::Endpoint(format!("Not an abstract endpoint: {}", self))),
+        }
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = Error;
+
+    fn from_str(address: &str) -> Result<Endpoint, Error> {
+        Endpoint::parse(address)
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Endpoint::Unix(path) => write!(f, "unix://{}", path.display()),
+            Endpoint::UnixAbstract(name) => write!(f, "unix-abstract:{}", name),
+            Endpoint::Tcp(addr) => write!(f, "tcp://{}", addr),
+            Endpoint::Tcp6(addr) => write!(f, "tcp6://{}", addr),
+            Endpoint::Tls(addr) => write!(f, "tls://{}", addr),
+            Endpoint::Ws(addr, path) => write!(f, "ws://{}{}", addr, path),
+            Endpoint::Mem(name) => write!(f, "mem://{}", name),
+        }
+    }
+}
+
+/// Check if "host:port" ends with valid port.
+fn has_port(addr: &str) -> bool {
+    match addr.rfind(':') {
+        Some(i) => !addr[..i].is_empty() && addr[i + 1..].parse::<u16>().is_ok(),
+        None => false,
+    }
+}
+
+/// Resolve IPv6 addresses only.
+fn resolve_v6(addr: &str) -> Result<Vec<SocketAddr>, Error> {
+    let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.filter(|a| a.is_ipv6()).collect();
+    if addrs.is_empty() {
+        return Err(Error::Endpoint(format!("No IPv6 address: {:?}", addr)));
+    }
+    Ok(addrs)
+}
+
+/// Socket address in the abstract namespace.
+#[cfg(target_os = "linux")]
+fn abstract_addr(name: &str) -> io::Result<::std::os::unix::net::SocketAddr> {
+    use std::os::linux::net::SocketAddrExt;
+    ::std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes())
+}
+
+/// Abstract sockets exist only on Linux.
+#[cfg(not(target_os = "linux"))]
+fn abstract_addr(_name: &str) -> io::Result<::std::os::unix::net::SocketAddr> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Abstract unix sockets are not supported"))
+}
+
+// -----------------------------
+// --- --- --- Tests --- --- ---
+// -----------------------------
+#[cfg(test)]
+mod tests {
+    use endpoint::*;
+
+    #[test]
+    fn parsing() {
+        let parse = |a: &str| Endpoint::parse(a).unwrap();
+        assert_eq!(parse("unix:///run/app/bus"), Endpoint::Unix(PathBuf::from("/run/app/bus")));
+        assert_eq!(parse("unix://app.sock"), Endpoint::Unix(PathBuf::from("app.sock")));
+        assert_eq!(parse("unix-abstract:app"), Endpoint::UnixAbstract("app".to_string()));
+        assert_eq!(parse("tcp://localhost:1234"), Endpoint::Tcp("localhost:1234".to_string()));
+        assert_eq!(parse("tcp6://[::1]:1234"), Endpoint::Tcp6("[::1]:1234".to_string()));
+        assert_eq!(parse("tls://bus.lab:4433"), Endpoint::Tls("bus.lab:4433".to_string()));
+        assert_eq!(parse("ws://0.0.0.0:8080/bus"), Endpoint::Ws("0.0.0.0:8080".to_string(), "/bus".to_string()));
+        assert_eq!(parse("mem://bus"), Endpoint::Mem("bus".to_string()));
+        assert_eq!(parse("ws://0.0.0.0:8080"), Endpoint::Ws("0.0.0.0:8080".to_string(), "/".to_string()));
+
+        // Old heuristic
+        assert_eq!(parse("/tmp/app.sock"), Endpoint::Unix(PathBuf::from("/tmp/app.sock")));
+        assert_eq!(parse("127.0.0.1:1234"), Endpoint::Tcp("127.0.0.1:1234".to_string()));
+
+        for address in ["udp://host:1", "unix://", "unix-abstract:", "tcp://host", "tcp6://::1:1", "ws://host/bus", "mem://"].iter() {
+            match Endpoint::parse(address) {
+                Err(Error::Endpoint(_)) => assert!(true),
+                _ => assert!(false, "{} should be invalid", address),
+            }
+        }
+        assert_eq!(parse("tcp6://[::1]:1").to_string(), "tcp6://[::1]:1");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn abstract_socket() {
+        let name = format!("con-test-{}", ::std::process::id());
+        let endpoint = Endpoint::UnixAbstract(name.clone());
+        let listener = endpoint.bind_abstract().unwrap();
+        assert!(endpoint.connect().is_ok());
+        let (stream, _) = listener.accept().unwrap();
+        assert_eq!(ConStream::new_unix(stream).peer_info().local_addr, Some(format!("@{}", name)));
+    }
+}
@@ -7,6 +7,21 @@ pub enum Error {
     ClientNotFound,
     Mutex,
     Empty,
+    Timeout,
+    Disconnected,
+    NameTooLong(String),
+    Remote(String),
+    Handshake(String),
+    Unauthorized,
+    Forbidden,
+    TooManyConnections,
+    RateLimited,
+    Pattern(String),
+    Reserved(String),
+    Malformed(String),
+    Endpoint(String),
+    Tls(String),
+    WebSocket(String),
     IO(io::Error),
 }
 
@@ -14,6 +29,21 @@ impl Error {
     pub fn description(&self) -> &str {
         match self {
             Error::ClientNotFound => "Client not found.",
+            Error::Timeout => "Request timed out.",
+            Error::Disconnected => "Peer disconnected.",
+            Error::NameTooLong(_) => "Name is longer than 255 bytes.",
+            Error::Remote(reason) => reason,
+            Error::Handshake(reason) => reason,
+            Error::Unauthorized => "Authentication failed.",
+            Error::Forbidden => "Not allowed.",
+            Error::TooManyConnections => "Too many connections.",
+            Error::RateLimited => "Rate limit exceeded.",
+            Error::Pattern(reason) => reason,
+            Error::Reserved(_) => "Message name is reserved.",
+            Error::Malformed(reason) => reason,
+            Error::Endpoint(reason) => reason,
+            Error::Tls(reason) => reason,
+            Error::WebSocket(reason) => reason,
             Error::IO(err) => err.description(),
             _ => "Unknown error.",
         }
@@ -0,0 +1,106 @@
+use std::time::{Duration, Instant};
+use utils;
+
+/// Liveness of the connection: last activity, pending ping and round-trip time.
+#[derive(Debug, Clone)]
+pub struct Heartbeat {
+    /// When the last message was received.
+    pub last_seen: Instant,
+    /// Round-trip time measured by the last answered ping.
+    pub rtt: Option<Duration>,
+    last_ping: Instant,
+    pending: Option<(u128, Instant)>,
+}
+
+impl Heartbeat {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        Heartbeat {
+            last_seen: now,
+            rtt: None,
+            last_ping: now,
+            pending: None,
+        }
+    }
+
+    /// Mark the peer as alive.
+    pub fn seen(&mut self) {
+        self.last_seen = Instant::now();
+    }
+
+    /// Id of the ping to send if the interval is over.
+    pub fn ping(&mut self, interval: Option<Duration>) -> Option<u128> {
+        let interval = interval?;
+        let now = Instant::now();
+        if now.duration_since(self.last_ping) < interval {
+            return None;
+        }
+
+        let id = utils::bid_to_u128(&utils::bid());
+        self.last_ping = now;
+        self.pending = Some((id, now));
+        Some(id)
+    }
+
+    /// Measure round-trip time of the answered ping.
+    pub fn pong(&mut self, id: u128) {
+        if let Some((ping_id, sent)) = self.pending {
+            if ping_id == id {
+                self.rtt = Some(sent.elapsed());
+                self.pending = None;
+            }
+        }
+    }
+
+    /// Check if nothing was received for too long.
+    pub fn is_idle(&self, timeout: Option<Duration>) -> bool {
+        match timeout {
+            Some(timeout) => self.last_seen.elapsed() > timeout,
+            None => false,
+        }
+    }
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Heartbeat::new()
+    }
+}
+
+/// How often to check the heartbeat.
+pub fn tick(ping_interval: Option<Duration>, idle_timeout: Option<Duration>) -> Duration {
+    let min = match (ping_interval, idle_timeout) {
+        (Some(a), Some(b)) => a.min(b),
+        (Some(a), None) | (None, Some(a)) => a,
+        (None, None) => return Duration::from_secs(1),
+    };
+    (min / 4).max(Duration::from_millis(10))
+}
+
+// -----------------------------
+// --- --- --- Tests --- --- ---
+// -----------------------------
+#[cfg(test)]
+mod tests {
+    use heartbeat::*;
+    use std::thread;
+
+    #[test]
+    fn ping_pong() {
+        let mut heartbeat = Heartbeat::new();
+        assert_eq!(heartbeat.ping(None), None);
+        assert_eq!(heartbeat.ping(Some(Duration::from_secs(60))), None);
+
+        let id = heartbeat.ping(Some(Duration::from_millis(0))).unwrap();
+        heartbeat.pong(id + 1);
+        assert!(heartbeat.rtt.is_none());
+        heartbeat.pong(id);
+        assert!(heartbeat.rtt.is_some());
+
+        thread::sleep(Duration::from_millis(20));
+        assert!(heartbeat.is_idle(Some(Duration::from_millis(10))));
+        assert!(!heartbeat.is_idle(None));
+        heartbeat.seen();
+        assert!(!heartbeat.is_idle(Some(Duration::from_millis(10))));
+    }
+}
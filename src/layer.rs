@@ -0,0 +1,27 @@
+use message::Msg;
+use server::SharedState;
+use std::sync::{Arc, Mutex};
+
+/// Decision of the layer about incoming message.
+pub enum Flow {
+    /// Pass the message to the next layer and then to handlers.
+    Next(Msg),
+    /// Stop handling and answer with the body (if the message is request).
+    Answer(Option<Vec<u8>>),
+    /// Stop handling and answer with the error.
+    Reject(String),
+}
+
+/// Middleware of the server message handling.
+///
+/// Layers are called in the order they were added before handlers
+/// and in reverse order after each handler.
+pub trait Layer<T>: Send + Sync {
+    /// Inspect or modify incoming message.
+    fn before(&self, msg: Msg, _state: &SharedState<T>, _ctx: &Arc<Mutex<T>>) -> Flow {
+        Flow::Next(msg)
+    }
+
+    /// Observe or modify handler's answer.
+    fn after(&self, _msg: &Msg, _ans: &mut Option<Vec<u8>>) {}
+}
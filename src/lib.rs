@@ -1,15 +1,51 @@
+extern crate base64;
+extern crate hmac;
+extern crate libc;
 extern crate rand;
+extern crate regex;
+extern crate sha1;
+extern crate sha2;
+#[cfg(feature = "tls")]
+extern crate rustls;
+#[cfg(feature = "tls")]
+extern crate rustls_pemfile;
+#[cfg(feature = "tls")]
+extern crate x509_parser;
+#[macro_use]
+extern crate tracing;
 
 pub mod utils;
 pub mod errors;
 pub mod stream;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod ws;
+pub mod mem;
+pub mod endpoint;
+pub mod outbox;
+pub mod stats;
+pub mod peer;
+pub mod auth;
+pub mod limit;
+pub mod heartbeat;
+pub mod layer;
+pub mod pattern;
+pub mod trace;
 pub mod message;
 pub mod server;
 pub mod client;
 
 pub use errors::Error;
 pub use server::Server;
+pub use endpoint::Endpoint;
 pub use server::ClientName;
+pub use server::Config;
+pub use server::DisconnectReason;
+pub use outbox::SlowClientPolicy;
+pub use auth::{Auth, Credentials};
+pub use limit::{LimitAction, RateLimit};
+pub use layer::{Flow, Layer};
 pub use client::Client;
-pub use message::Msg;
+pub use message::{Msg, Payload};
 pub use message::MsgName;
+pub use trace::TraceCtx;
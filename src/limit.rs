@@ -0,0 +1,126 @@
+use std::time::Instant;
+
+/// How many messages and bytes per second a client can send.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    /// Messages per second, 0 - unlimited.
+    pub msgs_per_sec: f64,
+    /// Max number of messages sent at once.
+    pub msgs_burst: f64,
+    /// Bytes per second, 0 - unlimited.
+    pub bytes_per_sec: f64,
+    /// Max number of bytes sent at once.
+    pub bytes_burst: f64,
+}
+
+/// What to do with the client exceeding the rate limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LimitAction {
+    /// Drop the message and answer with error.
+    Error,
+    /// Disconnect the client.
+    Disconnect,
+}
+
+/// Token bucket.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// Create full bucket.
+    pub fn new(rate: f64, burst: f64) -> Self {
+        TokenBucket {
+            rate,
+            burst,
+            tokens: burst,
+            last: Instant::now(),
+        }
+    }
+
+    /// Try to take n tokens.
+    pub fn take(&mut self, n: f64) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last);
+        let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) * 1e-9;
+        self.tokens = (self.tokens + secs * self.rate).min(self.burst);
+        self.last = now;
+
+        if self.tokens < n {
+            return false;
+        }
+        self.tokens -= n;
+        true
+    }
+}
+
+/// Rate limiter of one client.
+#[derive(Debug, Clone)]
+pub struct Limiter {
+    msgs: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+impl Limiter {
+    pub fn new(limit: RateLimit) -> Self {
+        let bucket = |rate: f64, burst: f64| {
+            if rate > 0.0 {
+                Some(TokenBucket::new(rate, burst.max(1.0)))
+            } else {
+                None
+            }
+        };
+
+        Limiter {
+            msgs: bucket(limit.msgs_per_sec, limit.msgs_burst),
+            bytes: bucket(limit.bytes_per_sec, limit.bytes_burst),
+        }
+    }
+
+    /// Check if the message of given length can pass.
+    pub fn check(&mut self, msg_len: usize) -> bool {
+        if let Some(ref mut msgs) = self.msgs {
+            if !msgs.take(1.0) {
+                return false;
+            }
+        }
+        if let Some(ref mut bytes) = self.bytes {
+            if !bytes.take(msg_len as f64) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+// -----------------------------
+// --- --- --- Tests --- --- ---
+// -----------------------------
+#[cfg(test)]
+mod tests {
+    use limit::*;
+
+    #[test]
+    fn limiter() {
+        let mut limiter = Limiter::new(RateLimit {
+            msgs_per_sec: 0.001,
+            msgs_burst: 3.0,
+            bytes_per_sec: 0.0,
+            bytes_burst: 0.0,
+        });
+        assert!(limiter.check(10_000) && limiter.check(1) && limiter.check(1));
+        assert!(!limiter.check(1));
+
+        let mut limiter = Limiter::new(RateLimit {
+            msgs_per_sec: 0.0,
+            msgs_burst: 0.0,
+            bytes_per_sec: 0.001,
+            bytes_burst: 100.0,
+        });
+        assert!(limiter.check(60) && !limiter.check(60) && limiter.check(40));
+    }
+}
@@ -0,0 +1,291 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+/// Bytes buffered in each direction before writes block (like socket buffer).
+static CAPACITY: usize = 256 * 1024;
+
+/// Listeners of the process by name.
+static LISTENERS: Mutex<Vec<(String, Sender<MemStream>)>> = Mutex::new(Vec::new());
+
+/// One direction of the connection.
+#[derive(Debug, Default)]
+struct Pipe {
+    state: Mutex<PipeState>,
+    changed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct PipeState {
+    buf: VecDeque<u8>,
+    closed: bool,
+}
+
+impl Pipe {
+    fn lock(&self) -> io::Result<MutexGuard<'_, PipeState>> {
+        self.state.lock().map_err(|_| io::Error::other("Memory pipe is poisoned"))
+    }
+
+    fn close(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.closed = true;
+        }
+        self.changed.notify_all();
+    }
+}
+
+/// End of the connection shared by clones, closed when the last one is dropped.
+#[derive(Debug)]
+struct End {
+    name: String,
+    rx: Arc<Pipe>,
+    tx: Arc<Pipe>,
+    nonblocking: AtomicBool,
+}
+
+impl Drop for End {
+    fn drop(&mut self) {
+        self.rx.close();
+        self.tx.close();
+    }
+}
+
+/// In-process duplex stream with the semantics of unix socket.
+#[derive(Debug)]
+pub struct MemStream {
+    end: Arc<End>,
+}
+
+impl MemStream {
+    /// Create connected pair of streams, `name` is the listener name.
+    pub fn pair(name: &str) -> (MemStream, MemStream) {
+        let (a, b) = (Arc::new(Pipe::default()), Arc::new(Pipe::default()));
+        let end = |rx: &Arc<Pipe>, tx: &Arc<Pipe>| MemStream {
+            end: Arc::new(End {
+                name: name.to_string(),
+                rx: rx.clone(),
+                tx: tx.clone(),
+                nonblocking: AtomicBool::new(false),
+            }),
+        };
+        (end(&a, &b), end(&b, &a))
+    }
+
+    /// Connect to the listener bound in this process.
+    pub fn connect(name: &str) -> io::Result<MemStream> {
+        let listeners = LISTENERS.lock().map_err(|_| io::Error::other("Memory listeners are poisoned"))?;
+        let refused = || io::Error::new(io::ErrorKind::ConnectionRefused, format!("No listener {:?}", name));
+        let tx = match listeners.iter().find(|(n, _)| n == name) {
+            Some((_, tx)) => tx,
+            None => return Err(refused()),
+        };
+        let (client, server) = MemStream::pair(name);
+        tx.send(server).map_err(|_| refused())?;
+        Ok(client)
+    }
+
+    /// Name of the listener the stream is connected to.
+    pub fn name(&self) -> &str {
+        &self.end.name
+    }
+
+    /// Clone sharing the same end, as duplicated socket does.
+    pub fn try_clone(&self) -> io::Result<MemStream> {
+        Ok(MemStream { end: self.end.clone() })
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.end.nonblocking.store(nonblocking, Ordering::SeqCst);
+        Ok(())
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match how {
+            Shutdown::Read => self.end.rx.close(),
+            Shutdown::Write => self.end.tx.close(),
+            Shutdown::Both => {
+                self.end.rx.close();
+                self.end.tx.close();
+            }
+        }
+        Ok(())
+    }
+
+    fn would_block(&self) -> io::Result<()> {
+        match self.end.nonblocking.load(Ordering::SeqCst) {
+            true => Err(io::Error::from(io::ErrorKind::WouldBlock)),
+            false => Ok(()),
+        }
+    }
+}
+
+impl Read for MemStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let pipe = &self.end.rx;
+        let mut state = pipe.lock()?;
+        while state.buf.is_empty() && !buf.is_empty() {
+            if state.closed {
+                return Ok(0);
+            }
+            self.would_block()?;
+            state = pipe.changed.wait(state).map_err(|_| io::Error::other("Memory pipe is poisoned"))?;
+        }
+
+        let n = buf.len().min(state.buf.len());
+        for (dst, src) in buf.iter_mut().zip(state.buf.drain(..n)) {
+            *dst = src;
+        }
+        pipe.changed.notify_all();
+        Ok(n)
+    }
+}
+
+impl Write for MemStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let pipe = &self.end.tx;
+        let mut state = pipe.lock()?;
+        loop {
+            if state.closed {
+                return Err(io::Error::from(io::ErrorKind::BrokenPipe));
+            }
+            if state.buf.len() < CAPACITY || buf.is_empty() {
+                break;
+            }
+            self.would_block()?;
+            state = pipe.changed.wait(state).map_err(|_| io::Error::other("Memory pipe is poisoned"))?;
+        }
+
+        let n = buf.len().min(CAPACITY - state.buf.len());
+        state.buf.extend(&buf[..n]);
+        pipe.changed.notify_all();
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Listener of in-process connections, unbound when dropped.
+#[derive(Debug)]
+pub struct MemListener {
+    name: String,
+    rx: Receiver<MemStream>,
+}
+
+impl MemListener {
+    /// Bind the name, fails if it's already bound in this process.
+    pub fn bind(name: &str) -> io::Result<MemListener> {
+        let mut listeners = LISTENERS.lock().map_err(|_| io::Error::other("Memory listeners are poisoned"))?;
+        if listeners.iter().any(|(n, _)| n == name) {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{:?} is already bound", name)));
+        }
+        let (tx, rx) = mpsc::channel();
+        listeners.push((name.to_string(), tx));
+        Ok(MemListener {
+            name: name.to_string(),
+            rx,
+        })
+    }
+
+    /// Wait for the next connection.
+    pub fn accept(&self) -> io::Result<MemStream> {
+        self.rx.recv().map_err(|_| io::Error::from(io::ErrorKind::ConnectionAborted))
+    }
+
+    /// Iterate over incoming connections.
+    pub fn incoming(&self) -> impl Iterator<Item = io::Result<MemStream>> + '_ {
+        ::std::iter::repeat_with(move || self.accept())
+    }
+}
+
+impl Drop for MemListener {
+    fn drop(&mut self) {
+        if let Ok(mut listeners) = LISTENERS.lock() {
+            listeners.retain(|(n, _)| *n != self.name);
+        }
+    }
+}
+
+// -----------------------------
+// --- --- --- Tests --- --- ---
+// -----------------------------
+#[cfg(test)]
+mod tests {
+    use client::Client;
+    use message::MsgName;
+    use peer::Transport;
+    use server::{ClientName, Server};
+    use std::thread;
+    use std::time::Duration;
+    use mem::*;
+
+    #[test]
+    fn duplex_pipe() {
+        let (mut a, mut b) = MemStream::pair("pipe");
+        let mut a_clone = a.try_clone().unwrap();
+
+        // Writer blocks on the full buffer until the reader catches up
+        let big = vec![7u8; CAPACITY * 2];
+        let writer = thread::spawn(move || a_clone.write_all(&big).unwrap());
+        let mut got = vec![0u8; CAPACITY * 2];
+        b.read_exact(&mut got).unwrap();
+        writer.join().unwrap();
+        assert!(got.iter().all(|b| *b == 7));
+
+        b.set_nonblocking(true).unwrap();
+        assert_eq!(b.read(&mut [0]).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        b.set_nonblocking(false).unwrap();
+
+        // Shutdown wakes blocked reader
+        let reader = thread::spawn(move || b.read(&mut [0]).unwrap());
+        thread::sleep(Duration::from_millis(50));
+        a.shutdown(Shutdown::Both).unwrap();
+        assert_eq!(reader.join().unwrap(), 0);
+        assert_eq!(a.write(b"x").unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+
+        // Dropping the last clone closes connection
+        let (a, mut b) = MemStream::pair("pipe");
+        drop(a);
+        assert_eq!(b.read(&mut [0]).unwrap(), 0);
+    }
+
+    #[test]
+    fn listener_names() {
+        assert_eq!(MemStream::connect("nobody").unwrap_err().kind(), io::ErrorKind::ConnectionRefused);
+
+        let listener = MemListener::bind("names").unwrap();
+        assert_eq!(MemListener::bind("names").unwrap_err().kind(), io::ErrorKind::AddrInUse);
+        let mut client = MemStream::connect("names").unwrap();
+        let mut server = listener.accept().unwrap();
+        client.write_all(b"hi").unwrap();
+        let mut buf = [0u8; 2];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hi");
+
+        drop(listener);
+        assert!(MemListener::bind("names").is_ok());
+    }
+
+    #[test]
+    fn in_process_client() {
+        let mut server = Server::new(Arc::new(Mutex::new(())));
+        server.on(ClientName::Any, MsgName::Is("echo"), |msg, _, _| msg.body.clone()).unwrap();
+        let state = server.state.clone();
+        thread::spawn(move || server.listen("mem://in-process").unwrap());
+        thread::sleep(Duration::from_millis(50));
+
+        let mut client = Client::connect("mem://in-process", (), Some("plugin")).unwrap();
+        let (ans_tx, ans_rx) = mpsc::channel();
+        client.req("echo", Some(Vec::from("hi")), ans_tx).unwrap();
+        assert_eq!(ans_rx.recv_timeout(Duration::from_secs(5)).unwrap().unwrap(), Some(Vec::from("hi")));
+
+        let state = state.lock().unwrap();
+        let peer = &state.client("plugin").unwrap().peer;
+        assert_eq!(peer.transport, Transport::Memory);
+        assert_eq!(peer.local_addr.as_deref(), Some("mem://in-process"));
+    }
+}
@@ -1,14 +1,83 @@
+use errors::Error;
+use pattern::Pattern;
 use std::io::{Read, Write};
 use std::sync::mpsc;
+use trace::{TraceCtx, TRACE_LEN};
 use utils;
 
 // Msg meta flags
-pub static MSG_WITH_BODY: u8   = 0b1_0_0_00000;
-pub static MSG_REQ: u8         = 0b0_1_0_00000;
+pub static MSG_WITH_BODY: u8   = 0b1000_0000;
+pub static MSG_REQ: u8         = 0b0100_0000;
+pub static MSG_PEER: u8        = 0b0010_0000;
+pub static MSG_ERR: u8         = 0b0001_0000;
+pub static MSG_TRACE: u8       = 0b0000_1000;
+pub static MSG_HEADERS: u8     = 0b0000_0100;
+
+/// Longest name (of message or peer) the frame can carry.
+pub static MAX_NAME_LEN: usize = 255;
+
+/// Prefix of message names reserved for control messages of the library.
+pub static RESERVED_PREFIX: &str = "$";
+
+/// Key-value metadata of the message.
+pub type Headers = Vec<(String, String)>;
 
 pub enum MsgName<'a> {
     Any,
     Is(&'a str),
+    Prefix(&'a str),
+    Glob(&'a str),
+    Regex(&'a str),
+}
+
+impl<'a> MsgName<'a> {
+    /// Compile the name to pattern.
+    pub fn to_pattern(&self) -> Result<Pattern, Error> {
+        match *self {
+            MsgName::Any => Ok(Pattern::Any),
+            MsgName::Is(name) => {
+                Msg::check_name(name)?;
+                Ok(Pattern::Is(name.to_string()))
+            }
+            MsgName::Prefix(prefix) => Ok(Pattern::Prefix(prefix.to_string())),
+            MsgName::Glob(glob) => Pattern::glob(glob),
+            MsgName::Regex(re) => Pattern::regex(re),
+        }
+    }
+}
+
+/// Body and headers of outgoing message.
+#[derive(Debug, Clone, Default)]
+pub struct Payload {
+    pub body: Option<Vec<u8>>,
+    pub headers: Headers,
+}
+
+impl Payload {
+    pub fn new(body: Option<Vec<u8>>) -> Self {
+        Payload {
+            body,
+            headers: Vec::new(),
+        }
+    }
+
+    /// Add header.
+    pub fn with_header(mut self, key: &str, val: &str) -> Self {
+        self.headers.push((key.to_string(), val.to_string()));
+        self
+    }
+}
+
+impl From<Option<Vec<u8>>> for Payload {
+    fn from(body: Option<Vec<u8>>) -> Self {
+        Payload::new(body)
+    }
+}
+
+impl From<(Option<Vec<u8>>, Headers)> for Payload {
+    fn from((body, headers): (Option<Vec<u8>>, Headers)) -> Self {
+        Payload { body, headers }
+    }
 }
 
 pub enum MsgReading {
@@ -16,12 +85,27 @@ pub enum MsgReading {
     Stop,
 }
 
+/// Why reading of the stream ended.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReadEnd {
+    /// The peer closed the stream.
+    Closed,
+    /// Reading failed.
+    Error,
+    /// The callback asked to stop.
+    Stopped,
+}
+
 #[derive(Debug, Clone)]
 pub struct Msg {
     pub client: String,
     pub id: u128,
     pub req: bool,
+    pub err: bool,
     pub name: String,
+    pub peer: Option<String>,
+    pub trace: Option<TraceCtx>,
+    pub headers: Headers,
     pub body: Option<Vec<u8>>,
     pub ans_tx: Option<mpsc::Sender<Option<Vec<u8>>>>,
 }
@@ -32,37 +116,119 @@ impl Msg {
         Msg {
             id: id,
             req: (meta & MSG_REQ) == MSG_REQ,
+            err: (meta & MSG_ERR) == MSG_ERR,
             name: name.to_string(),
+            peer: None,
+            trace: None,
+            headers: Vec::new(),
             client: client.to_string(),
             body: None,
             ans_tx: None,
         }        
     }
 
-    /// Create new message from bytes.
-    pub fn from_bytes(bin: &[u8], client_id: &str) -> Self {
+    /// Create new message from bytes, fails if fields overrun the frame.
+    pub fn from_bytes(bin: &[u8], client_id: &str) -> Result<Self, Error> {
+        let mut pos = 0;
+
         // Id, meta, name
-        let id: u128 = utils::bid_to_u128(&bin[0..12]);
-        let meta = bin[12];
-        let name_end: usize = bin[13] as usize + 14;
-        let name = String::from_utf8_lossy(&bin[14..name_end]).to_string();
+        let id: u128 = utils::bid_to_u128(take(bin, &mut pos, 12)?);
+        let meta = take(bin, &mut pos, 1)?[0];
+        let name_len = take(bin, &mut pos, 1)?[0] as usize;
+        let name = String::from_utf8_lossy(take(bin, &mut pos, name_len)?).to_string();
+
+        // Peer
+        let mut peer: Option<String> = None;
+        if meta & MSG_PEER != 0 {
+            let peer_len = take(bin, &mut pos, 1)?[0] as usize;
+            peer = Some(String::from_utf8_lossy(take(bin, &mut pos, peer_len)?).to_string());
+        }
+
+        // Trace context
+        let mut trace: Option<TraceCtx> = None;
+        if meta & MSG_TRACE != 0 {
+            trace = Some(TraceCtx::from_bytes(take(bin, &mut pos, TRACE_LEN)?));
+        }
+
+        // Headers, pairs must fit in the section
+        let mut headers = Vec::new();
+        if meta & MSG_HEADERS != 0 {
+            let section_len = utils::bytes_to_u32(take(bin, &mut pos, 4)?) as usize;
+            let section = take(bin, &mut pos, section_len)?;
+            let mut section_pos = 0;
+            while section_pos < section.len() {
+                let key_len = take(section, &mut section_pos, 1)?[0] as usize;
+                let key = String::from_utf8_lossy(take(section, &mut section_pos, key_len)?).to_string();
+                let val_len = take(section, &mut section_pos, 2)?;
+                let val_len = (val_len[0] as usize) << 8 | val_len[1] as usize;
+                let val = String::from_utf8_lossy(take(section, &mut section_pos, val_len)?).to_string();
+                headers.push((key, val));
+            }
+        }
 
         // Body
         let mut body: Option<Vec<u8>> = None;
-        if bin.len() > name_end {
-            let body_len = utils::bytes_to_u64(&bin[name_end..name_end + 8]) as usize;
-            let body_start = name_end + 8;
-            let body_end = body_start + body_len;
-            body = Some(Vec::from(&bin[body_start..body_end]));
+        if meta & MSG_WITH_BODY != 0 {
+            let body_len = utils::bytes_to_u64(take(bin, &mut pos, 8)?) as usize;
+            body = Some(Vec::from(take(bin, &mut pos, body_len)?));
         }
 
-        Msg {
+        Ok(Msg {
             id: id,
             req: (meta & MSG_REQ) == MSG_REQ,
+            err: (meta & MSG_ERR) == MSG_ERR,
             name: name,
+            peer,
+            trace,
+            headers,
             client: client_id.to_string(),
             body: body,
             ans_tx: None,
+        })
+    }
+
+    /// Get length of the message at the start of the buffer
+    /// or None if there is not enough data to know it.
+    pub fn frame_len(bin: &[u8]) -> Option<usize> {
+        if bin.len() < 14 {
+            return None;
+        }
+        let meta = bin[12];
+        let mut len = 14 + bin[13] as usize;
+
+        if meta & MSG_PEER != 0 {
+            if bin.len() < len + 1 {
+                return None;
+            }
+            len += 1 + bin[len] as usize;
+        }
+
+        if meta & MSG_TRACE != 0 {
+            len += TRACE_LEN;
+        }
+
+        if meta & MSG_HEADERS != 0 {
+            if bin.len() < len + 4 {
+                return None;
+            }
+            len = len.saturating_add(4 + utils::bytes_to_u32(&bin[len..len + 4]) as usize);
+        }
+
+        if meta & MSG_WITH_BODY != 0 {
+            if bin.len() < len + 8 {
+                return None;
+            }
+            len = len.saturating_add(8).saturating_add(utils::bytes_to_u64(&bin[len..len + 8]) as usize);
+        }
+
+        Some(len)
+    }
+
+    /// Check that the name fits in the frame.
+    pub fn check_name(name: &str) -> Result<(), Error> {
+        match name.len() > MAX_NAME_LEN {
+            true => Err(Error::NameTooLong(name.to_string())),
+            false => Ok(()),
         }
     }
 
@@ -78,6 +244,81 @@ impl Msg {
         self
     }
 
+    /// Set peer (recipient or sender) name.
+    pub fn with_peer(mut self, peer: &str) -> Self {
+        self.peer = Some(peer.to_string());
+        self
+    }
+
+    /// Add header.
+    pub fn with_header(mut self, key: &str, val: &str) -> Self {
+        self.headers.push((key.to_string(), val.to_string()));
+        self
+    }
+
+    /// Check if the name is reserved for control messages.
+    pub fn is_reserved(name: &str) -> bool {
+        name.starts_with(RESERVED_PREFIX)
+    }
+
+    /// Get value of the first header with this key.
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+
+    /// Set body and headers.
+    pub fn with_payload(mut self, payload: Payload) -> Self {
+        self.body = payload.body;
+        self.headers = payload.headers;
+        self
+    }
+
+    /// Set trace context.
+    pub fn with_trace(mut self, trace: TraceCtx) -> Self {
+        self.trace = Some(trace);
+        self
+    }
+
+    /// Create binary message from this one.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut meta = 0u8;
+        if self.body.is_some() { meta |= MSG_WITH_BODY }
+        if self.req { meta |= MSG_REQ }
+        if self.peer.is_some() { meta |= MSG_PEER }
+        if self.err { meta |= MSG_ERR }
+        if self.trace.is_some() { meta |= MSG_TRACE }
+        if !self.headers.is_empty() { meta |= MSG_HEADERS }
+
+        let mut msg = Msg::raw(self.id, meta, &self.name, None);
+        if let Some(ref peer) = self.peer {
+            msg.push(peer.len() as u8);
+            msg.extend_from_slice(peer.as_bytes());
+        }
+        if let Some(ref trace) = self.trace {
+            msg.extend_from_slice(&trace.to_bytes());
+        }
+        if !self.headers.is_empty() {
+            // Keys longer than 255 and values longer than 65535 bytes are cut
+            let mut section = Vec::new();
+            for (key, val) in self.headers.iter() {
+                let key = &key.as_bytes()[..key.len().min(0xff)];
+                let val = &val.as_bytes()[..val.len().min(0xffff)];
+                section.push(key.len() as u8);
+                section.extend_from_slice(key);
+                section.extend_from_slice(&[(val.len() >> 8) as u8, val.len() as u8]);
+                section.extend_from_slice(val);
+            }
+            msg.extend_from_slice(&utils::u32_to_bytes(section.len() as u32));
+            msg.extend_from_slice(&section);
+        }
+        if let Some(ref b) = self.body {
+            msg.extend_from_slice(&utils::u64_to_bytes(b.len() as u64));
+            msg.extend_from_slice(b);
+        }
+
+        msg
+    }
+
     /// Create binary message.
     pub fn raw(id: u128, meta: u8, name: &str, body: Option<Vec<u8>>) -> Vec<u8> {
         let body_len = match body {
@@ -124,8 +365,8 @@ impl Msg {
         stream.flush().expect("Cannot flush buffer");
     }
 
-    /// Start reading stream.
-    pub fn read<F>(stream: &mut Read, mut f: F)
+    /// Read stream until it ends or the callback stops it.
+    pub fn read<F>(stream: &mut Read, mut f: F) -> ReadEnd
     where
         F: FnMut(&[u8]) -> MsgReading,
     {
@@ -134,83 +375,106 @@ impl Msg {
 
         let mut read_buff = [0; CHUNK_SIZE];
         let mut msg_buff = Vec::with_capacity(BUFF_SIZE);
-        let mut read_len: usize = 0;
-        let mut meta: u8 = 0;
-        let mut with_body: bool = false;
-        let mut name_len: u8 = 0;
-        let mut name_end: usize = 0;
-        let mut body_len: u64 = 0;
-        let mut msg_end: usize = 0;
         loop {
-            if let Ok(n) = stream.read(&mut read_buff) {
-                if n == 0 {
-                    break;
+            match stream.read(&mut read_buff) {
+                Ok(0) => return ReadEnd::Closed,
+                Ok(n) => msg_buff.extend_from_slice(&read_buff[0..n]),
+                // Reading error
+                Err(err) => {
+                    warn!(error = %err, "Cannot read stream");
+                    return ReadEnd::Error;
                 }
+            }
 
-                msg_buff.extend_from_slice(&read_buff[0..n]);
-                read_len += n;
-
-                while msg_buff.len() > 0 {
-                    // Meta
-                    if meta == 0 && read_len >= 13 {
-                        meta = msg_buff[12];
-                        with_body = (meta & MSG_WITH_BODY) != 0;
-                    }
-
-                    // Name len
-                    if name_len == 0 && read_len >= 14 {
-                        name_len = msg_buff[13];
-                        name_end = (14 + name_len) as usize;
-                        if !with_body {
-                            msg_end = name_end
-                        };
-                    }
-
-                    // Body len
-                    if with_body && name_len > 0 && body_len == 0 && read_len >= name_end + 8 {
-                        body_len = utils::bytes_to_u64(&msg_buff[name_end..name_end + 8]);
-                        msg_end = name_end as usize + 8 + body_len as usize;
-                    }
-
-                    // Got full message
-                    if msg_end > 0 && read_len >= msg_end {
-                        // Handle message
-                        match f(&msg_buff[..msg_end]) {
-                            MsgReading::Continue => (),
-                            MsgReading::Stop => return,
-                        }
-
-                        // Clean up
-                        meta = 0;
-                        with_body = false;
-                        name_len = 0;
-                        name_end = 0;
-                        body_len = 0;
-                        if read_len == msg_end {
-                            msg_buff.clear();
-                            read_len = 0;
-                            msg_end = 0;
-                            // reading: exact full message
-                            break;
-                        } else {
-                            msg_buff.drain(..msg_end);
-                            read_len -= msg_end;
-                            msg_end = 0;
-                            // reading: full message with extra stuff
-                            continue;
-                        }
-                    } else {
-                        // reading: not enough data
-                        break;
-                    }
+            // Handle all full messages
+            while let Some(msg_end) = Msg::frame_len(&msg_buff) {
+                if msg_buff.len() < msg_end {
+                    break;
                 }
-            } else {
-                // Reading error
-                break;
+                match f(&msg_buff[..msg_end]) {
+                    MsgReading::Continue => (),
+                    MsgReading::Stop => return ReadEnd::Stopped,
+                }
+                msg_buff.drain(..msg_end);
             }
         }
+    }
+}
+
+/// Take `len` bytes at `pos` and move it, fails if the buffer is too short.
+fn take<'a>(bin: &'a [u8], pos: &mut usize, len: usize) -> Result<&'a [u8], Error> {
+    match pos.checked_add(len) {
+        Some(end) if end <= bin.len() => {
+            let part = &bin[*pos..end];
+            *pos = end;
+            Ok(part)
+        }
+        _ => Err(Error::Malformed(format!("Frame is too short: {} of {} bytes", pos.saturating_add(len), bin.len()))),
+    }
+}
+
+// -----------------------------
+// --- --- --- Tests --- --- ---
+// -----------------------------
+#[cfg(test)]
+mod tests {
+    use message::*;
+
+    #[test]
+    fn encoding_and_decoding() {
+        let trace = TraceCtx::new().child();
+        let msg = Msg::new("", 42, MSG_REQ, "some-msg")
+            .with_peer("client-b")
+            .with_trace(trace)
+            .with_header("content-type", "text/plain")
+            .with_header("reply-to", "")
+            .with_str_body("body");
+        let bin = msg.to_bytes();
+        assert_eq!(Msg::frame_len(&bin), Some(bin.len()));
+
+        let decoded = Msg::from_bytes(&bin, "cli").unwrap();
+        assert_eq!(decoded.id, 42);
+        assert!(decoded.req && !decoded.err);
+        assert_eq!(decoded.name, "some-msg");
+        assert_eq!(decoded.peer, Some("client-b".to_string()));
+        assert_eq!(decoded.trace, Some(trace));
+        assert_eq!(decoded.header("content-type"), Some("text/plain"));
+        assert_eq!(decoded.header("reply-to"), Some(""));
+        assert_eq!(decoded.headers.len(), 2);
+        assert_eq!(decoded.body, Some(Vec::from("body")));
+    }
+
+    #[test]
+    fn reading_split_stream() {
+        let mut bin = Msg::new("", 1, 0, "a").with_str_body("first").to_bytes();
+        bin.extend(Msg::new("", 2, 0, "b").to_bytes());
+        assert_eq!(Msg::frame_len(&bin[..10]), None);
+
+        let mut names = Vec::new();
+        let end = Msg::read(&mut &bin[..], |msg| {
+            names.push(Msg::from_bytes(msg, "").unwrap().name);
+            MsgReading::Continue
+        });
+        assert_eq!(names, vec!["a", "b"]);
+        assert_eq!(end, ReadEnd::Closed);
+    }
+
+    #[test]
+    fn malformed_headers() {
+        // Key overruns the section
+        let mut bin = Msg::raw(1, MSG_HEADERS, "a", None);
+        bin.extend_from_slice(&[0, 0, 0, 1, 200]);
+        assert_eq!(Msg::frame_len(&bin), Some(bin.len()));
+        assert!(Msg::from_bytes(&bin, "").is_err());
+
+        // Value overruns the section
+        let mut bin = Msg::raw(1, MSG_HEADERS, "a", None);
+        bin.extend_from_slice(&[0, 0, 0, 4, 1, b'k', 0xff, 0xff]);
+        assert!(Msg::from_bytes(&bin, "").is_err());
 
-        // Return disconnection message
-        f(&Msg::raw(0, 0, "disconnect", None));
+        // Section overruns the frame
+        let mut bin = Msg::raw(1, MSG_HEADERS, "a", None);
+        bin.extend_from_slice(&[0xff, 0xff, 0xff, 0xff]);
+        assert!(Msg::from_bytes(&bin, "").is_err());
     }
 }
@@ -0,0 +1,216 @@
+use std::collections::VecDeque;
+use std::io::Write;
+use std::net::Shutdown;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use stream::ConStream;
+
+/// Encoded message shared between all recipients.
+pub type Frame = Arc<Vec<u8>>;
+
+/// What to do with the client whose outbound queue is full.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SlowClientPolicy {
+    /// Drop the oldest queued message to make room for the new one.
+    DropOldest,
+    /// Drop the new message.
+    DropNewest,
+    /// Disconnect the client.
+    Disconnect,
+}
+
+/// Result of queuing a frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Push {
+    Queued,
+    Dropped,
+    Disconnected,
+}
+
+#[derive(Debug)]
+struct Queue {
+    frames: VecDeque<Frame>,
+    capacity: usize,
+    policy: SlowClientPolicy,
+    dropped: u64,
+    closed: bool,
+    kill: bool,
+}
+
+impl Queue {
+    fn new(capacity: usize, policy: SlowClientPolicy) -> Self {
+        Queue {
+            frames: VecDeque::with_capacity(capacity.min(64)),
+            capacity,
+            policy,
+            dropped: 0,
+            closed: false,
+            kill: false,
+        }
+    }
+
+    /// Add frame to the queue according to the policy.
+    fn push(&mut self, frame: Frame) -> Push {
+        if self.closed {
+            self.dropped += 1;
+            return Push::Dropped;
+        }
+        if self.frames.len() < self.capacity {
+            self.frames.push_back(frame);
+            return Push::Queued;
+        }
+
+        self.dropped += 1;
+        match self.policy {
+            SlowClientPolicy::DropOldest => {
+                self.frames.pop_front();
+                self.frames.push_back(frame);
+                Push::Dropped
+            }
+            SlowClientPolicy::DropNewest => Push::Dropped,
+            SlowClientPolicy::Disconnect => {
+                self.dropped += self.frames.len() as u64;
+                self.frames.clear();
+                self.closed = true;
+                self.kill = true;
+                Push::Disconnected
+            }
+        }
+    }
+}
+
+/// Bounded outbound queue of the client drained by its own writer thread.
+#[derive(Debug, Clone)]
+pub struct Outbox {
+    inner: Arc<(Mutex<Queue>, Condvar)>,
+}
+
+impl Outbox {
+    /// Create queue and start writing its frames to the stream.
+    pub fn new(stream: ConStream, capacity: usize, policy: SlowClientPolicy) -> Self {
+        let outbox = Outbox {
+            inner: Arc::new((Mutex::new(Queue::new(capacity, policy)), Condvar::new())),
+        };
+
+        let writer = outbox.clone();
+        thread::spawn(move || writer.drain(stream));
+
+        outbox
+    }
+
+    /// Queue frame without blocking.
+    pub fn push(&self, frame: Frame) -> Push {
+        let (ref queue, ref cvar) = *self.inner;
+        let mut queue = match queue.lock() {
+            Ok(q) => q,
+            Err(_) => return Push::Dropped,
+        };
+        let result = queue.push(frame);
+        cvar.notify_one();
+        result
+    }
+
+    /// Number of frames waiting to be written.
+    pub fn len(&self) -> usize {
+        match self.inner.0.lock() {
+            Ok(q) => q.frames.len(),
+            Err(_) => 0,
+        }
+    }
+
+    /// Check if there is nothing to write.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of frames dropped for this client.
+    pub fn dropped(&self) -> u64 {
+        match self.inner.0.lock() {
+            Ok(q) => q.dropped,
+            Err(_) => 0,
+        }
+    }
+
+    /// Stop writer thread after queued frames are written.
+    pub fn close(&self) {
+        let (ref queue, ref cvar) = *self.inner;
+        if let Ok(mut queue) = queue.lock() {
+            queue.closed = true;
+        }
+        cvar.notify_one();
+    }
+
+    /// Write frames to the stream until the queue is closed.
+    fn drain(&self, mut stream: ConStream) {
+        let (ref queue, ref cvar) = *self.inner;
+        loop {
+            let frame = {
+                let mut queue = match queue.lock() {
+                    Ok(q) => q,
+                    Err(_) => return,
+                };
+                while queue.frames.is_empty() && !queue.closed {
+                    queue = match cvar.wait(queue) {
+                        Ok(q) => q,
+                        Err(_) => return,
+                    };
+                }
+                if queue.kill {
+                    break;
+                }
+                match queue.frames.pop_front() {
+                    Some(frame) => frame,
+                    None => return,
+                }
+            };
+
+            if stream.write_all(&frame).and_then(|_| stream.flush()).is_err() {
+                break;
+            }
+        }
+
+        // Slow or broken peer, reader thread will clean up the client
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+}
+
+// -----------------------------
+// --- --- --- Tests --- --- ---
+// -----------------------------
+#[cfg(test)]
+mod tests {
+    use outbox::*;
+
+    fn frame(n: u8) -> Frame {
+        Arc::new(vec![n])
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let mut queue = Queue::new(2, SlowClientPolicy::DropOldest);
+        assert_eq!(queue.push(frame(1)), Push::Queued);
+        assert_eq!(queue.push(frame(2)), Push::Queued);
+        assert_eq!(queue.push(frame(3)), Push::Dropped);
+        assert_eq!(queue.dropped, 1);
+        assert_eq!(queue.frames.iter().map(|f| f[0]).collect::<Vec<u8>>(), vec![2, 3]);
+    }
+
+    #[test]
+    fn drop_newest() {
+        let mut queue = Queue::new(2, SlowClientPolicy::DropNewest);
+        queue.push(frame(1));
+        queue.push(frame(2));
+        assert_eq!(queue.push(frame(3)), Push::Dropped);
+        assert_eq!(queue.frames.iter().map(|f| f[0]).collect::<Vec<u8>>(), vec![1, 2]);
+    }
+
+    #[test]
+    fn disconnect_on_overflow() {
+        let mut queue = Queue::new(1, SlowClientPolicy::Disconnect);
+        queue.push(frame(1));
+        assert_eq!(queue.push(frame(2)), Push::Disconnected);
+        assert!(queue.closed && queue.kill && queue.frames.is_empty());
+        assert_eq!(queue.push(frame(3)), Push::Dropped);
+        assert_eq!(queue.dropped, 3);
+    }
+}
@@ -0,0 +1,111 @@
+use errors::Error;
+use message::Msg;
+use regex::{self, Regex};
+use std::fmt;
+
+/// Compiled matcher of message or client names.
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    Any,
+    Is(String),
+    Prefix(String),
+    Glob(String, Regex),
+    Regex(Regex),
+}
+
+impl Pattern {
+    /// Compile glob where `*` matches one dot-separated segment (or its part),
+    /// `**` matches anything and `?` matches single character except the dot.
+    pub fn glob(glob: &str) -> Result<Pattern, Error> {
+        let mut re = String::with_capacity(glob.len() * 2 + 2);
+        re.push('^');
+        let mut chars = glob.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    re.push_str(".*");
+                }
+                '*' => re.push_str("[^.]*"),
+                '?' => re.push_str("[^.]"),
+                c => re.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        re.push('$');
+        match Pattern::regex(&re)? {
+            Pattern::Regex(re) => Ok(Pattern::Glob(glob.to_string(), re)),
+            pattern => Ok(pattern),
+        }
+    }
+
+    /// Compile regular expression.
+    pub fn regex(re: &str) -> Result<Pattern, Error> {
+        match Regex::new(re) {
+            Ok(re) => Ok(Pattern::Regex(re)),
+            Err(err) => Err(Error::Pattern(err.to_string())),
+        }
+    }
+
+    /// Check if the pattern is written for reserved names.
+    pub fn is_reserved(&self) -> bool {
+        match self {
+            Pattern::Is(val) | Pattern::Prefix(val) | Pattern::Glob(val, _) => Msg::is_reserved(val),
+            Pattern::Any | Pattern::Regex(_) => false,
+        }
+    }
+
+    /// Check if the name matches the pattern.
+    pub fn matches(&self, name: &str) -> bool {
+        match self {
+            Pattern::Any => true,
+            Pattern::Is(val) => val == name,
+            Pattern::Prefix(prefix) => name.starts_with(prefix.as_str()),
+            Pattern::Glob(_, re) | Pattern::Regex(re) => re.is_match(name),
+        }
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Pattern::Any => write!(f, "any"),
+            Pattern::Is(val) => write!(f, "is:{}", val),
+            Pattern::Prefix(prefix) => write!(f, "prefix:{}", prefix),
+            Pattern::Glob(glob, _) => write!(f, "glob:{}", glob),
+            Pattern::Regex(re) => write!(f, "regex:{}", re.as_str()),
+        }
+    }
+}
+
+// -----------------------------
+// --- --- --- Tests --- --- ---
+// -----------------------------
+#[cfg(test)]
+mod tests {
+    use pattern::*;
+
+    #[test]
+    fn glob() {
+        let p = Pattern::glob("sensor.*.temp").unwrap();
+        assert!(p.matches("sensor.kitchen.temp"));
+        assert!(!p.matches("sensor.kitchen.floor.temp"));
+        assert!(!p.matches("sensor.kitchen.temperature"));
+
+        let p = Pattern::glob("sensor.**").unwrap();
+        assert!(p.matches("sensor.kitchen.floor.temp"));
+        assert!(!p.matches("sensors.x"));
+
+        let p = Pattern::glob("v?.(x)").unwrap();
+        assert!(p.matches("v1.(x)"));
+        assert!(!p.matches("v..(x)"));
+        assert_eq!(p.to_string(), "glob:v?.(x)");
+    }
+
+    #[test]
+    fn prefix_and_regex() {
+        assert!(Pattern::Prefix("log.".to_string()).matches("log.error"));
+        assert!(!Pattern::Prefix("log.".to_string()).matches("login"));
+        assert!(Pattern::regex("^job-[0-9]+$").unwrap().matches("job-42"));
+        assert!(Pattern::regex("(").is_err());
+    }
+}
@@ -0,0 +1,85 @@
+use std::net::SocketAddr;
+use std::os::unix::io::RawFd;
+use std::time::SystemTime;
+
+/// Transport the peer is connected with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transport {
+    Tcp,
+    Tls,
+    WebSocket,
+    Unix,
+    Memory,
+}
+
+/// Information about the other end of connection.
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    pub transport: Transport,
+    /// Local address: "ip:port", socket path, "@name" of abstract socket or "mem://name".
+    pub local_addr: Option<String>,
+    /// Remote address: "ip:port" or socket path (if bound).
+    pub remote_addr: Option<String>,
+    /// Peer process credentials (unix sockets only).
+    pub cred: Option<PeerCred>,
+    /// Subject of the verified client certificate (TLS only), e.g. "CN=client-a".
+    pub cert_subject: Option<String>,
+    pub connected_at: SystemTime,
+}
+
+/// Credentials of the process on the other end of unix socket.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PeerCred {
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: i32,
+}
+
+impl PeerInfo {
+    /// Where the peer comes from: remote ip for tcp or user id for unix sockets
+    /// (none for in-process peers).
+    pub fn origin(&self) -> Option<String> {
+        match self.transport {
+            Transport::Tcp | Transport::Tls | Transport::WebSocket => self
+                .remote_addr
+                .as_ref()
+                .and_then(|a| a.parse::<SocketAddr>().ok())
+                .map(|a| a.ip().to_string()),
+            Transport::Unix => self.cred.map(|c| format!("uid:{}", c.uid)),
+            Transport::Memory => None,
+        }
+    }
+}
+
+impl PeerCred {
+    /// Get peer credentials of the unix socket (SO_PEERCRED).
+    #[cfg(target_os = "linux")]
+    pub fn from_fd(fd: RawFd) -> Option<PeerCred> {
+        let mut cred = libc::ucred { pid: 0, uid: 0, gid: 0 };
+        let mut len = ::std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut cred as *mut libc::ucred as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret != 0 {
+            return None;
+        }
+
+        Some(PeerCred {
+            uid: cred.uid,
+            gid: cred.gid,
+            pid: cred.pid,
+        })
+    }
+
+    /// Get peer credentials of the unix socket (not supported on this platform).
+    #[cfg(not(target_os = "linux"))]
+    pub fn from_fd(_fd: RawFd) -> Option<PeerCred> {
+        None
+    }
+}
@@ -1,30 +1,120 @@
+use auth::{self, Auth, Authorizer};
+use endpoint::Endpoint;
 use errors::Error;
-use message::{Msg, MsgName, MsgReading, MSG_WITH_BODY};
+use heartbeat::{self, Heartbeat};
+use layer::{Flow, Layer};
+use limit::{LimitAction, Limiter, RateLimit};
+use message::{Msg, MsgName, MsgReading, Payload, ReadEnd, MSG_ERR, MSG_REQ, MSG_WITH_BODY};
+use outbox::{Frame, Outbox, Push, SlowClientPolicy};
+use pattern::Pattern;
+use peer::{PeerCred, PeerInfo};
+use stats::{self, Stats};
 use std::fs;
-use std::io;
+use std::io::{self, Read, Write};
 use std::net::{Shutdown, TcpListener};
-use std::os::unix::net::UnixListener;
-use std::sync::{Arc, Mutex};
+use std::os::unix::fs::{self as unix_fs, FileTypeExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex, Weak};
+use std::panic::{self, AssertUnwindSafe};
 use std::thread;
+use std::time::{Duration, Instant};
 use stream::ConStream;
+#[cfg(feature = "tls")]
+use tls::TlsStream;
+use ws::WsStream;
+use trace::TraceCtx;
+use tracing::field;
 use utils;
 
+/// Control messages the server handles itself.
+static SYSTEM_NAMES: [&str; 9] = [
+    "$ping", "$pong", "$join", "$leave", "$provide", "$clients", "$handlers", "$stats", "$info",
+];
+
 pub type SharedState<T> = Arc<Mutex<State<T>>>;
 pub type HandlerFunc<T> = fn(Msg, SharedState<T>, Arc<Mutex<T>>) -> Option<Vec<u8>>;
+pub type ConnectHook<T> = fn(&ClientInfo, Arc<Mutex<T>>);
+pub type DisconnectHook<T> = fn(&ClientInfo, DisconnectReason, Arc<Mutex<T>>);
 
 pub struct Handler<T> {
-    func: HandlerFunc<T>,
+    func: Option<HandlerFunc<T>>,
+    ans: Option<mpsc::Sender<Result<Option<Vec<u8>>, Error>>>,
     once: bool,
     called: bool,
     msg_id: Option<u128>,
-    msg_name: Option<String>,
+    msg_name: Pattern,
     client_id: Option<String>,
-    client_name: Option<String>,
+    client_name: Pattern,
+    calls: u64,
 }
 
 pub enum ClientName<'a> {
     Any,
     Is(&'a str),
+    Prefix(&'a str),
+    Glob(&'a str),
+    Regex(&'a str),
+}
+
+impl<'a> ClientName<'a> {
+    /// Compile the name to pattern.
+    pub fn to_pattern(&self) -> Result<Pattern, Error> {
+        match *self {
+            ClientName::Any => Ok(Pattern::Any),
+            ClientName::Is(name) => {
+                Msg::check_name(name)?;
+                Ok(Pattern::Is(name.to_string()))
+            }
+            ClientName::Prefix(prefix) => Ok(Pattern::Prefix(prefix.to_string())),
+            ClientName::Glob(glob) => Pattern::glob(glob),
+            ClientName::Regex(re) => Pattern::regex(re),
+        }
+    }
+}
+
+/// What to do when connecting client asks for the name already in use.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NameCollision {
+    /// Refuse the handshake of the new client.
+    Reject,
+    /// Disconnect the client holding the name.
+    Evict,
+    /// Give the new client the name with numeric suffix: "name-2", "name-3"...
+    Suffix,
+}
+
+/// Why the client was disconnected.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DisconnectReason {
+    /// The client closed the connection.
+    Closed,
+    /// Reading from the client failed.
+    Error,
+    /// Disconnected by `Server::disconnect`.
+    Kicked,
+    /// Another client took its name.
+    Evicted,
+    /// Sent nothing for longer than the idle timeout.
+    Timeout,
+    /// Exceeded the rate limit.
+    RateLimited,
+    /// Failed authentication or sent messages before it.
+    Unauthorized,
+    /// Didn't keep up with its outbound queue.
+    SlowClient,
+}
+
+/// Snapshot of the client passed to lifecycle hooks.
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+    pub id: String,
+    pub name: Option<String>,
+    pub peer: PeerInfo,
+    pub topics: Vec<String>,
+    pub provides: Vec<String>,
+    pub authenticated: bool,
 }
 
 #[derive(Debug)]
@@ -32,10 +122,19 @@ pub struct ConnectedClient {
     pub id: String,
     pub name: Option<String>,
     pub stream: ConStream,
+    pub outbox: Outbox,
+    pub peer: PeerInfo,
+    pub topics: Vec<String>,
+    pub provides: Vec<String>,
+    pub authenticated: bool,
+    pub heartbeat: Heartbeat,
+    challenge: Vec<u8>,
+    handshaken: bool,
+    closing: Option<DisconnectReason>,
 }
 
 impl ConnectedClient {
-    pub fn new(name: Option<&str>, stream: ConStream) -> Self {
+    pub fn new(name: Option<&str>, stream: ConStream, outbox: Outbox) -> Self {
         let name = match name {
             Some(name) => Some(name.to_string()),
             None => None,
@@ -44,7 +143,119 @@ impl ConnectedClient {
         ConnectedClient {
             id: utils::uid(),
             name: name,
+            peer: stream.peer_info(),
             stream: stream,
+            outbox,
+            topics: Vec::new(),
+            provides: Vec::new(),
+            authenticated: true,
+            heartbeat: Heartbeat::new(),
+            challenge: Vec::new(),
+            handshaken: false,
+            closing: None,
+        }
+    }
+
+    /// Copy the client's public fields.
+    pub fn info(&self) -> ClientInfo {
+        ClientInfo {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            peer: self.peer.clone(),
+            topics: self.topics.clone(),
+            provides: self.provides.clone(),
+            authenticated: self.authenticated,
+        }
+    }
+
+    /// Queue encoded message for sending.
+    pub fn push(&self, frame: Frame) -> Push {
+        self.outbox.push(frame)
+    }
+}
+
+impl Drop for ConnectedClient {
+    fn drop(&mut self) {
+        self.outbox.close();
+    }
+}
+
+/// What to do when the unix socket file already exists.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExistingSocket {
+    /// Replace the socket if no server answers on it.
+    ReplaceStale,
+    /// Refuse to listen.
+    Fail,
+}
+
+/// Server settings.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Max number of messages queued for one client.
+    pub outbox_capacity: usize,
+    /// What to do with the client that doesn't keep up.
+    pub slow_client_policy: SlowClientPolicy,
+    /// How long to wait for the client's answer.
+    pub req_timeout: Duration,
+    /// How to handle clients with the same name.
+    pub name_collision: NameCollision,
+    /// How to authenticate clients in the handshake.
+    pub auth: Auth,
+    /// Check of unix socket peers before accepting them.
+    pub authorize: Option<Authorizer>,
+    /// Max number of connected clients.
+    pub max_connections: Option<usize>,
+    /// Max number of clients from one ip address (tcp) or user (unix).
+    pub max_connections_per_peer: Option<usize>,
+    /// Limit of messages and bytes sent by one client.
+    pub rate_limit: Option<RateLimit>,
+    /// What to do with the client exceeding the rate limit.
+    pub rate_limit_action: LimitAction,
+    /// How often to ping connected clients.
+    pub ping_interval: Option<Duration>,
+    /// Disconnect the client that sent nothing (not even pong) for this long.
+    pub idle_timeout: Option<Duration>,
+    /// Log handlers running longer than this.
+    pub slow_handler: Option<Duration>,
+    /// Who can send introspection requests ($clients, $handlers, $stats, $info), none by default.
+    pub introspect: Option<fn(&ConnectedClient) -> bool>,
+    /// What to do if the unix socket file exists.
+    pub existing_socket: ExistingSocket,
+    /// Permissions of the unix socket file, e.g. 0o660.
+    pub socket_mode: Option<u32>,
+    /// Owner (uid, gid) of the unix socket file.
+    pub socket_owner: (Option<u32>, Option<u32>),
+    /// Hold exclusive lock of "<socket>.lock" file while listening.
+    pub lock_file: bool,
+    /// Certificate and client verification of "tls://" listeners, see `tls::server_config`.
+    #[cfg(feature = "tls")]
+    pub tls: Option<Arc<rustls::ServerConfig>>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            outbox_capacity: 1024,
+            slow_client_policy: SlowClientPolicy::DropOldest,
+            req_timeout: Duration::from_secs(30),
+            name_collision: NameCollision::Reject,
+            auth: Auth::None,
+            authorize: None,
+            max_connections: None,
+            max_connections_per_peer: None,
+            rate_limit: None,
+            rate_limit_action: LimitAction::Error,
+            ping_interval: Some(Duration::from_secs(15)),
+            idle_timeout: Some(Duration::from_secs(45)),
+            slow_handler: Some(Duration::from_secs(1)),
+            introspect: None,
+            existing_socket: ExistingSocket::ReplaceStale,
+            socket_mode: None,
+            socket_owner: (None, None),
+            lock_file: false,
+            #[cfg(feature = "tls")]
+            tls: None,
         }
     }
 }
@@ -52,6 +263,54 @@ impl ConnectedClient {
 pub struct State<T> {
     pub clients: Vec<ConnectedClient>,
     pub handlers: Vec<Handler<T>>,
+    pub layers: Vec<Arc<dyn Layer<T>>>,
+    pub config: Config,
+    pub stats: Stats,
+    pub on_connect: Vec<ConnectHook<T>>,
+    pub on_handshake: Vec<ConnectHook<T>>,
+    pub on_disconnect: Vec<DisconnectHook<T>>,
+}
+
+impl<T> State<T> {
+    /// Find index of the client by id or name.
+    pub fn find_client(&self, client: &str) -> Option<usize> {
+        self.clients.iter().position(|c| match c.name {
+            Some(ref name) => c.id == client || name == client,
+            None => c.id == client,
+        })
+    }
+
+    /// Find client by id or name.
+    pub fn client(&self, client: &str) -> Option<&ConnectedClient> {
+        self.find_client(client).map(|i| &self.clients[i])
+    }
+
+    /// Queue frame for the client with given id.
+    fn push_to(&mut self, client_id: &str, frame: Frame) -> Push {
+        match self.clients.iter().position(|c| c.id == client_id) {
+            Some(i) => self.push(i, frame),
+            None => Push::Dropped,
+        }
+    }
+
+    /// Queue frame for the client and count it if dropped.
+    fn push(&mut self, client_index: usize, frame: Frame) -> Push {
+        let len = frame.len();
+        let client = &mut self.clients[client_index];
+        let result = client.push(frame);
+        if result == Push::Disconnected {
+            warn!(client_id = %client.id, "Slow client disconnected");
+            client.closing = Some(DisconnectReason::SlowClient);
+            // Writer may be blocked on the peer, shutdown wakes it and ends the reader
+            client.stream.shutdown(Shutdown::Both).unwrap_or(());
+        }
+        if result == Push::Queued {
+            self.stats.sent(&client.id, len);
+        } else {
+            self.stats.dropped += 1;
+        }
+        result
+    }
 }
 
 pub struct Server<T> {
@@ -62,54 +321,53 @@ pub struct Server<T> {
 impl<T: Send + Sync + 'static> Server<T> {
     ///  Construct new server
     pub fn new(ctx: Arc<Mutex<T>>) -> Server<T> {
-        // Setup handlers
-        let mut handlers = Vec::with_capacity(5);
-        handlers.push(Handler {
-            func: Server::<T>::handle_handshake,
-            once: false,
-            called: false,
-            msg_id: None,
-            msg_name: Some("handshake".to_string()),
-            client_id: None,
-            client_name: None,
-        });
+        Server::with_config(ctx, Config::default())
+    }
 
+    ///  Construct new server with given settings
+    pub fn with_config(ctx: Arc<Mutex<T>>, config: Config) -> Server<T> {
         // Create initial struct
         let state = State {
             clients: Vec::new(),
-            handlers: handlers,
+            handlers: Vec::new(),
+            layers: Vec::new(),
+            config,
+            stats: Stats::default(),
+            on_connect: Vec::new(),
+            on_handshake: Vec::new(),
+            on_disconnect: Vec::new(),
         };
         let state = Arc::new(Mutex::new(state));
 
+        // Ping clients and drop the idle ones
+        let weak_state = Arc::downgrade(&state);
+        thread::spawn(move || Server::heartbeat(weak_state));
+
         Server { state, ctx }
     }
 
-    ///  Listen given address
+    ///  Listen given address, see `Endpoint` for supported formats
     pub fn listen(&mut self, address: &str) -> Result<(), Error> {
-        if address.starts_with("/") && address.ends_with(".sock") {
-            Server::<T>::handle_unix_clients(address, self.state.clone(), self.ctx.clone())?;
-        } else {
-            Server::<T>::handle_tcp_clients(address, self.state.clone(), self.ctx.clone())?;
-        };
-
-        Ok(())
+        let endpoint = Endpoint::parse(address)?;
+        Server::<T>::handle_endpoint(&endpoint, self.state.clone(), self.ctx.clone())
     }
 
     /// Listen all addresses in separated threads.
     pub fn listen_all(&mut self, addresses: &'static [&str]) -> Result<(), Error> {
-        addresses.iter().for_each(|address| {
+        let endpoints = addresses
+            .iter()
+            .map(|address| Endpoint::parse(address))
+            .collect::<Result<Vec<Endpoint>, Error>>()?;
+
+        for endpoint in endpoints {
             let state = self.state.clone();
             let ctx = self.ctx.clone();
             thread::spawn(move || {
-                if address.starts_with("/") && address.ends_with(".sock") {
-                    Server::<T>::handle_unix_clients(address, state, ctx)
-                        .expect("cannot listen unix socket");
-                } else {
-                    Server::<T>::handle_tcp_clients(address, state, ctx)
-                        .expect("cannot listen tcp socket");
-                };
+                if let Err(err) = Server::<T>::handle_endpoint(&endpoint, state, ctx) {
+                    error!(address = %endpoint, error = ?err, "Cannot listen address");
+                }
             });
-        });
+        }
 
         Ok(())
     }
@@ -124,87 +382,361 @@ impl<T: Send + Sync + 'static> Server<T> {
         self.subs(client_name, msg_name, true, h)
     }
 
+    /// Add middleware layer
+    pub fn layer<L: Layer<T> + 'static>(&mut self, layer: L) -> Result<(), Error> {
+        let mut state = match self.state.lock() {
+            Ok(s) => s,
+            Err(_) => return Err(Error::Mutex),
+        };
+        state.layers.push(Arc::new(layer));
+        Ok(())
+    }
+
+    /// Call the hook when a client connects, before its handshake.
+    /// Hooks run after the server state is unlocked, with the snapshot of the client.
+    pub fn on_connect(&mut self, hook: ConnectHook<T>) -> Result<(), Error> {
+        let mut state = match self.state.lock() {
+            Ok(s) => s,
+            Err(_) => return Err(Error::Mutex),
+        };
+        state.on_connect.push(hook);
+        Ok(())
+    }
+
+    /// Call the hook when a client completes the handshake.
+    /// Hooks run after the server state is unlocked, with the snapshot of the client.
+    pub fn on_handshake(&mut self, hook: ConnectHook<T>) -> Result<(), Error> {
+        let mut state = match self.state.lock() {
+            Ok(s) => s,
+            Err(_) => return Err(Error::Mutex),
+        };
+        state.on_handshake.push(hook);
+        Ok(())
+    }
+
+    /// Call the hook when a client disconnects, after it's removed from the state.
+    /// Hooks run after the server state is unlocked, with the snapshot of the client.
+    pub fn on_disconnect(&mut self, hook: DisconnectHook<T>) -> Result<(), Error> {
+        let mut state = match self.state.lock() {
+            Ok(s) => s,
+            Err(_) => return Err(Error::Mutex),
+        };
+        state.on_disconnect.push(hook);
+        Ok(())
+    }
+
     /// Broadcast message to all connected clients
     pub fn broadcast(
         state: &SharedState<T>,
         msg_name: &str,
-        body: Option<Vec<u8>>,
+        body: impl Into<Payload>,
     ) -> Result<(), Error> {
-        let state = match state.lock() {
+        let mut state = match state.lock() {
             Ok(s) => s,
             Err(_) => return Err(Error::Mutex),
         };
 
-        let msg_id = utils::bid();
-        let msg_meta = if body.is_none() { 0u8 } else { MSG_WITH_BODY };
-        let name_bin = msg_name.as_bytes();
+        let frame = Server::<T>::outgoing(msg_name, body.into())?;
 
-        for client in state.clients.iter() {
-            let mut s = client.stream.try_clone()?;
-            Msg::write(&mut s, &msg_id, &[msg_meta], name_bin, &body);
+        for i in 0..state.clients.len() {
+            if state.clients[i].authenticated {
+                state.push(i, frame.clone());
+            }
         }
 
         Ok(())
     }
 
-    /// Send message to client
+    /// Send message to the first client with this name
     pub fn send(
         state: &SharedState<T>,
         client_name: &str,
         msg_name: &str,
-        body: Option<Vec<u8>>,
+        body: impl Into<Payload>,
     ) -> Result<(), Error> {
-        let state = match state.lock() {
+        let mut found = false;
+        Server::send_where(
+            state,
+            |c| {
+                let matched = !found && c.name.as_deref() == Some(client_name);
+                found = found || matched;
+                matched
+            },
+            msg_name,
+            body,
+        )?;
+        if !found {
+            return Err(Error::ClientNotFound);
+        }
+        Ok(())
+    }
+
+    /// Send message to the client with this id
+    pub fn send_to_id(
+        state: &SharedState<T>,
+        client_id: &str,
+        msg_name: &str,
+        body: impl Into<Payload>,
+    ) -> Result<(), Error> {
+        match Server::send_where(state, |c| c.id == client_id, msg_name, body)? {
+            0 => Err(Error::ClientNotFound),
+            _ => Ok(()),
+        }
+    }
+
+    /// Send message to all clients with this name, returns number of recipients
+    pub fn send_to_name(
+        state: &SharedState<T>,
+        client_name: &str,
+        msg_name: &str,
+        body: impl Into<Payload>,
+    ) -> Result<usize, Error> {
+        let is_named = |c: &ConnectedClient| c.name.as_deref() == Some(client_name);
+        match Server::send_where(state, is_named, msg_name, body)? {
+            0 => Err(Error::ClientNotFound),
+            count => Ok(count),
+        }
+    }
+
+    /// Send message to clients matching the predicate, returns number of recipients
+    pub fn send_where<F: FnMut(&ConnectedClient) -> bool>(
+        state: &SharedState<T>,
+        mut predicate: F,
+        msg_name: &str,
+        body: impl Into<Payload>,
+    ) -> Result<usize, Error> {
+        let mut state = match state.lock() {
             Ok(s) => s,
             Err(_) => return Err(Error::Mutex),
         };
 
-        let msg_id = utils::bid();
-        let msg_meta = if body.is_none() { 0u8 } else { MSG_WITH_BODY };
-        let name_bin = msg_name.as_bytes();
+        let frame = Server::<T>::outgoing(msg_name, body.into())?;
 
-        let client = state.clients.iter().find(|c| match c.name {
-            Some(ref name) => name == client_name,
-            None => false,
+        let mut count = 0;
+        for i in 0..state.clients.len() {
+            if state.clients[i].authenticated && predicate(&state.clients[i]) {
+                state.push(i, frame.clone());
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Send message to clients joined the topic, returns number of clients it's queued for
+    pub fn publish(
+        state: &SharedState<T>,
+        topic: &str,
+        msg_name: &str,
+        body: impl Into<Payload>,
+    ) -> Result<usize, Error> {
+        let mut state = match state.lock() {
+            Ok(s) => s,
+            Err(_) => return Err(Error::Mutex),
+        };
+
+        let frame = Server::<T>::outgoing(msg_name, body.into())?;
+
+        let mut count = 0;
+        for i in 0..state.clients.len() {
+            let client = &state.clients[i];
+            if client.authenticated
+                && client.topics.iter().any(|t| t == topic)
+                && state.push(i, frame.clone()) == Push::Queued
+            {
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Send request to client (by id or name) and wait for the answer
+    pub fn req(
+        state: &SharedState<T>,
+        client: &str,
+        msg_name: &str,
+        body: impl Into<Payload>,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        Server::<T>::check_outgoing(msg_name)?;
+        let msg_id = utils::bid_to_u128(&utils::bid());
+        let mut msg = Msg::new("", msg_id, MSG_REQ, msg_name).with_payload(body.into());
+        msg.trace = TraceCtx::current().map(|t| t.child());
+
+        Server::request(state, client, msg)
+    }
+
+    /// Send request message to client and wait for the answer.
+    fn request(state: &SharedState<T>, client: &str, msg: Msg) -> Result<Option<Vec<u8>>, Error> {
+        let (ans_tx, ans_rx) = mpsc::channel();
+        let msg_id = msg.id;
+
+        let mut locked_state = match state.lock() {
+            Ok(s) => s,
+            Err(_) => return Err(Error::Mutex),
+        };
+
+        let i = match locked_state.find_client(client) {
+            Some(i) => i,
+            None => return Err(Error::ClientNotFound),
+        };
+
+        // Wait for the answer with the same id from the same client
+        let client_id = locked_state.clients[i].id.clone();
+        locked_state.handlers.push(Handler {
+            func: None,
+            ans: Some(ans_tx),
+            once: true,
+            called: false,
+            msg_id: Some(msg_id),
+            msg_name: Pattern::Is(msg.name.clone()),
+            client_id: Some(client_id),
+            client_name: Pattern::Any,
+            calls: 0,
         });
 
-        if let Some(client) = client {
-            let mut s = client.stream.try_clone()?;
-            Msg::write(&mut s, &msg_id, &[msg_meta], name_bin, &body);
+        locked_state.push(i, Arc::new(msg.to_bytes()));
+        let timeout = locked_state.config.req_timeout;
+        drop(locked_state);
+
+        match ans_rx.recv_timeout(timeout) {
+            Ok(ans) => ans,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                let mut locked_state = match state.lock() {
+                    Ok(s) => s,
+                    Err(_) => return Err(Error::Mutex),
+                };
+                locked_state.handlers.retain(|h| h.msg_id != Some(msg_id));
+                Err(Error::Timeout)
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(Error::Disconnected),
         }
+    }
+
+    /// Render server metrics in Prometheus text format
+    pub fn metrics(state: &SharedState<T>) -> Result<String, Error> {
+        let state = match state.lock() {
+            Ok(s) => s,
+            Err(_) => return Err(Error::Mutex),
+        };
+        Ok(state.stats.to_prometheus(state.clients.len()))
+    }
 
+    /// Serve metrics over http (GET /metrics) on given tcp address. Will block thread.
+    pub fn serve_metrics(state: &SharedState<T>, address: &str) -> Result<(), Error> {
+        let listener = TcpListener::bind(address)?;
+        for conn in listener.incoming() {
+            let mut stream = match conn {
+                Ok(s) => s,
+                Err(_) => continue,
+            };
+            stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+            // Read request head
+            let mut head = Vec::with_capacity(512);
+            let mut buf = [0u8; 512];
+            while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() < 8192 {
+                match stream.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => head.extend_from_slice(&buf[..n]),
+                }
+            }
+
+            let is_metrics = head.starts_with(b"GET /metrics ") || head.starts_with(b"GET / ");
+            let (status, body) = if is_metrics {
+                ("200 OK", Server::metrics(state)?)
+            } else {
+                ("404 Not Found", String::from("Not found.\n"))
+            };
+            let res = format!(
+                "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            stream.write_all(res.as_bytes()).unwrap_or(());
+        }
         Ok(())
     }
 
     /// Disconnect peer
     pub fn disconnect(state: &SharedState<T>, client: &str) -> Result<(), Error> {
-        let state = match state.lock() {
+        let mut state = match state.lock() {
             Ok(s) => s,
             Err(_) => return Err(Error::Mutex),
         };
 
-        let maybe_i = state.clients.iter().position(|c| match c.name {
-            Some(ref name) => c.id == client || name == client,
-            None => c.id == client,
-        });
-
-        let i = match maybe_i {
+        let i = match state.find_client(client) {
             Some(i) => i,
             None => return Err(Error::ClientNotFound),
         };
 
+        state.clients[i].closing = Some(DisconnectReason::Kicked);
         state.clients[i].stream.shutdown(Shutdown::Both)?;
 
         Ok(())
     }
 
+    /// Ping clients and disconnect idle ones until the server is dropped
+    fn heartbeat(state: Weak<Mutex<State<T>>>) {
+        loop {
+            let tick = {
+                let state = match state.upgrade() {
+                    Some(s) => s,
+                    None => return,
+                };
+                let mut state = match state.lock() {
+                    Ok(s) => s,
+                    Err(_) => return,
+                };
+
+                let ping_interval = state.config.ping_interval;
+                let idle_timeout = state.config.idle_timeout;
+                for i in 0..state.clients.len() {
+                    if state.clients[i].heartbeat.is_idle(idle_timeout) {
+                        // Reader thread will clean up the client
+                        info!(client_id = %state.clients[i].id, "Idle client disconnected");
+                        state.clients[i].closing = Some(DisconnectReason::Timeout);
+                        let _ = state.clients[i].stream.shutdown(Shutdown::Both);
+                        continue;
+                    }
+                    if !state.clients[i].authenticated {
+                        continue;
+                    }
+                    if let Some(id) = state.clients[i].heartbeat.ping(ping_interval) {
+                        let frame = Arc::new(Msg::raw(id, 0, "$ping", None));
+                        state.push(i, frame);
+                    }
+                }
+
+                heartbeat::tick(ping_interval, idle_timeout)
+            };
+            thread::sleep(tick);
+        }
+    }
+
+    /// Accept clients of the endpoint.
+    fn handle_endpoint(endpoint: &Endpoint, state: SharedState<T>, ctx: Arc<Mutex<T>>) -> Result<(), Error> {
+        match endpoint {
+            Endpoint::Unix(_) | Endpoint::UnixAbstract(_) => Server::<T>::handle_unix_clients(endpoint, state, ctx),
+            Endpoint::Tcp(_) | Endpoint::Tcp6(_) => Server::<T>::handle_tcp_clients(endpoint, state, ctx),
+            #[cfg(feature = "tls")]
+            Endpoint::Tls(_) => Server::<T>::handle_tls_clients(endpoint, state, ctx),
+            #[cfg(not(feature = "tls"))]
+            Endpoint::Tls(_) => Err(Error::Tls("Built without \"tls\" feature.".to_string())),
+            Endpoint::Ws(..) => Server::<T>::handle_ws_clients(endpoint, state, ctx),
+            Endpoint::Mem(_) => Server::<T>::handle_mem_clients(endpoint, state, ctx),
+        }
+    }
+
     /// Start listening tcp stream
     fn handle_tcp_clients(
-        address: &str,
+        address: &Endpoint,
         state: SharedState<T>,
         ctx: Arc<Mutex<T>>,
     ) -> Result<(), Error> {
-        let listener = TcpListener::bind(address)?;
+        let listener = address.bind_tcp()?;
         let state = state.clone();
 
         // Handle incoming connections
@@ -214,176 +746,772 @@ impl<T: Send + Sync + 'static> Server<T> {
                     let stream = ConStream::new_tcp(s);
                     Server::<T>::handle_client(state.clone(), stream, ctx.clone())?;
                 }
-                Err(_) => break,
+                Err(err) => {
+                    error!(%address, error = %err, "Cannot accept connection");
+                    break;
+                }
             }
         }
         Ok(())
     }
 
-    /// Start listening unix stream.
-    fn handle_unix_clients(
-        addr: &str,
+    /// Start listening tcp stream and accept TLS sessions.
+    #[cfg(feature = "tls")]
+    fn handle_tls_clients(
+        address: &Endpoint,
         state: SharedState<T>,
         ctx: Arc<Mutex<T>>,
     ) -> Result<(), Error> {
-        let listener = Server::<T>::open_sock(addr)?;
-        let state = state.clone();
+        let config = match state.lock() {
+            Ok(s) => s.config.tls.clone(),
+            Err(_) => return Err(Error::Mutex),
+        };
+        let config = match config {
+            Some(config) => config,
+            None => return Err(Error::Tls(format!("No TLS settings to listen {}", address))),
+        };
+        let listener = address.bind_tcp()?;
 
         // Handle incoming connections
         for conn in listener.incoming() {
             match conn {
                 Ok(s) => {
-                    let stream = ConStream::new_unix(s);
-                    Server::handle_client(state.clone(), stream, ctx.clone())?;
+                    // Slow handshake must not block other clients
+                    let (state, ctx, config) = (state.clone(), ctx.clone(), config.clone());
+                    let remote = s.peer_addr().ok();
+                    thread::spawn(move || match TlsStream::accept(s, config) {
+                        Ok(s) => {
+                            let stream = ConStream::new_tls(s);
+                            if let Err(err) = Server::<T>::handle_client(state, stream, ctx) {
+                                error!(error = ?err, "Cannot handle client");
+                            }
+                        }
+                        Err(err) => warn!(remote = ?remote, error = ?err, "TLS handshake failed"),
+                    });
+                }
+                Err(err) => {
+                    error!(%address, error = %err, "Cannot accept connection");
+                    break;
                 }
-                Err(_) => break,
             }
         }
         Ok(())
     }
 
-    /// Handle new client in separated thread.
-    fn handle_client(
+    /// Start listening tcp stream and accept WebSocket upgrades.
+    fn handle_ws_clients(
+        address: &Endpoint,
         state: SharedState<T>,
-        stream: ConStream,
         ctx: Arc<Mutex<T>>,
     ) -> Result<(), Error> {
-        // Create client
-        let client = ConnectedClient::new(None, stream.try_clone()?);
-        let cli_id = client.id.clone();
-
-        // Read stream in new thread
-        let state_clone = state.clone();
-        thread::spawn(move || {
-            Server::handle_messages(&cli_id, state_clone, stream, ctx).unwrap();
-        });
-
-        // Add new client to server state
-        let mut locked_state = match state.lock() {
-            Ok(s) => s,
-            Err(_) => return Err(Error::Mutex),
+        let path = match address {
+            Endpoint::Ws(_, path) => path.clone(),
+            _ => return Err(Error::Endpoint(format!("Not a WebSocket endpoint: {}", address))),
         };
-        locked_state.clients.push(client);
-        drop(locked_state);
+        let listener = address.bind_tcp()?;
 
+        // Handle incoming connections
+        for conn in listener.incoming() {
+            match conn {
+                Ok(s) => {
+                    // Slow upgrade must not block other clients
+                    let (state, ctx, path) = (state.clone(), ctx.clone(), path.clone());
+                    let remote = s.peer_addr().ok();
+                    thread::spawn(move || match WsStream::accept(s, &path) {
+                        Ok(s) => {
+                            let stream = ConStream::new_ws(s);
+                            if let Err(err) = Server::<T>::handle_client(state, stream, ctx) {
+                                error!(error = ?err, "Cannot handle client");
+                            }
+                        }
+                        Err(err) => warn!(remote = ?remote, error = ?err, "WebSocket upgrade failed"),
+                    });
+                }
+                Err(err) => {
+                    error!(%address, error = %err, "Cannot accept connection");
+                    break;
+                }
+            }
+        }
         Ok(())
     }
 
-    /// Handle messages from connected client.
-    fn handle_messages(
-        client_id: &str,
+    /// Start listening unix stream.
+    fn handle_unix_clients(
+        addr: &Endpoint,
         state: SharedState<T>,
-        mut stream: ConStream,
         ctx: Arc<Mutex<T>>,
     ) -> Result<(), Error> {
-        Msg::read(&mut stream, |msg| {
-            match Server::handle_message(&client_id, state.clone(), msg, ctx.clone()) {
-                Ok(_) => MsgReading::Continue,
-                Err(_) => MsgReading::Stop,
-            }
-        });
-
-        // Handle client disconnecting
-        let mut state = match state.lock() {
-            Ok(s) => s,
+        let (listener, _lock) = match state.lock() {
+            Ok(s) => Server::<T>::open_sock(addr, &s.config)?,
             Err(_) => return Err(Error::Mutex),
         };
+        let state = state.clone();
 
-        let maybe_client_index = state.clients.iter().position(|c| c.id == client_id);
-        if let Some(client_index) = maybe_client_index {
-            state.clients.remove(client_index);
-        }
+        // Handle incoming connections
+        for conn in listener.incoming() {
+            match conn {
+                Ok(s) => {
+                    // Check peer process
+                    let authorize = match state.lock() {
+                        Ok(s) => s.config.authorize,
+                        Err(_) => return Err(Error::Mutex),
+                    };
+                    if let Some(authorize) = authorize {
+                        match PeerCred::from_fd(s.as_raw_fd()) {
+                            Some(ref cred) if authorize(cred) => (),
+                            cred => {
+                                warn!(cred = ?cred, "Unix peer rejected by authorizer");
+                                continue;
+                            }
+                        }
+                    }
 
+                    let stream = ConStream::new_unix(s);
+                    Server::handle_client(state.clone(), stream, ctx.clone())?;
+                }
+                Err(err) => {
+                    error!(address = %addr, error = %err, "Cannot accept connection");
+                    break;
+                }
+            }
+        }
         Ok(())
     }
 
-    /// Find message handler and call it.
-    fn handle_message(
-        client_id: &str,
+    /// Start listening in-process connections.
+    fn handle_mem_clients(
+        address: &Endpoint,
         state: SharedState<T>,
-        msg_buff: &[u8],
         ctx: Arc<Mutex<T>>,
     ) -> Result<(), Error> {
-        let msg = Msg::from_bytes(&msg_buff, &client_id.clone());
-        let state_clone = state.clone();
+        let listener = address.bind_mem()?;
+
+        // Handle incoming connections
+        for conn in listener.incoming() {
+            match conn {
+                Ok(s) => {
+                    let stream = ConStream::new_mem(s);
+                    Server::<T>::handle_client(state.clone(), stream, ctx.clone())?;
+                }
+                Err(err) => {
+                    error!(%address, error = %err, "Cannot accept connection");
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle new client in separated thread.
+    fn handle_client(
+        state: SharedState<T>,
+        stream: ConStream,
+        ctx: Arc<Mutex<T>>,
+    ) -> Result<(), Error> {
+        let mut locked_state = match state.lock() {
+            Ok(s) => s,
+            Err(_) => return Err(Error::Mutex),
+        };
+
+        // Check connection limits
+        let mut over_limit = match locked_state.config.max_connections {
+            Some(max) => locked_state.clients.len() >= max,
+            None => false,
+        };
+        if let Some(max) = locked_state.config.max_connections_per_peer {
+            if let Some(origin) = stream.peer_info().origin() {
+                let n = locked_state
+                    .clients
+                    .iter()
+                    .filter(|c| c.peer.origin().as_ref() == Some(&origin))
+                    .count();
+                over_limit = over_limit || n >= max;
+            }
+        }
+        if over_limit {
+            warn!(remote = ?stream.peer_info().remote_addr, "Too many connections, client rejected");
+            Server::<T>::reject(stream, Error::TooManyConnections);
+            return Ok(());
+        }
+
+        // Challenge is generated up front, the client is refused without it
+        let challenge = match locked_state.config.auth.needs_challenge() {
+            true => match auth::challenge() {
+                Ok(challenge) => Some(challenge),
+                Err(err) => {
+                    error!(remote = ?stream.peer_info().remote_addr, error = %err.description(), "Client rejected");
+                    Server::<T>::reject(stream, err);
+                    return Ok(());
+                }
+            },
+            false => None,
+        };
+
+        // Create client
+        let outbox = Outbox::new(
+            stream.try_clone()?,
+            locked_state.config.outbox_capacity,
+            locked_state.config.slow_client_policy,
+        );
+        let mut client = ConnectedClient::new(None, stream.try_clone()?, outbox);
+        let cli_id = client.id.clone();
+        locked_state.stats.connections += 1;
+        info!(
+            client_id = %cli_id,
+            transport = ?client.peer.transport,
+            remote = ?client.peer.remote_addr,
+            "Client connected"
+        );
+
+        // Authentication
+        client.authenticated = matches!(locked_state.config.auth, Auth::None);
+        if let Some(challenge) = challenge {
+            client.challenge = challenge;
+            let id = utils::bid_to_u128(&utils::bid());
+            let msg = Msg::new(&cli_id, id, 0, "$challenge").with_body(&client.challenge);
+            client.push(Arc::new(msg.to_bytes()));
+        }
+
+        // Add new client to server state
+        let info = client.info();
+        let hooks = locked_state.on_connect.clone();
+        locked_state.clients.push(client);
+        drop(locked_state);
+        for hook in hooks.iter() {
+            hook(&info, ctx.clone());
+        }
+
+        // Read stream in new thread, after hooks so they see connect before handshake
+        thread::spawn(move || {
+            if let Err(err) = Server::handle_messages(&cli_id, state, stream, ctx) {
+                error!(client_id = %cli_id, error = ?err, "Client handling failed");
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Refuse the connection with handshake error the client reads.
+    fn reject(mut stream: ConStream, err: Error) {
+        let id = utils::bid_to_u128(&utils::bid());
+        let msg = Msg::new("", id, MSG_ERR, "$handshake").with_str_body(err.description());
+        stream.write_all(&msg.to_bytes()).unwrap_or(());
+        stream.shutdown(Shutdown::Both).unwrap_or(());
+    }
+
+    /// Handle messages from connected client.
+    fn handle_messages(
+        client_id: &str,
+        state: SharedState<T>,
+        mut stream: ConStream,
+        ctx: Arc<Mutex<T>>,
+    ) -> Result<(), Error> {
+        let (mut limiter, limit_action) = match state.lock() {
+            Ok(s) => (s.config.rate_limit.map(Limiter::new), s.config.rate_limit_action),
+            Err(_) => return Err(Error::Mutex),
+        };
+
+        let mut reason = DisconnectReason::Error;
+        let end = Msg::read(&mut stream, |msg| {
+            // Check rate limit
+            if let Some(ref mut limiter) = limiter {
+                if !limiter.check(msg.len()) {
+                    warn!(%client_id, action = ?limit_action, "Rate limit exceeded");
+                    if limit_action == LimitAction::Disconnect {
+                        reason = DisconnectReason::RateLimited;
+                        return MsgReading::Stop;
+                    }
+                    let msg = match Msg::from_bytes(msg, client_id) {
+                        Ok(msg) => msg,
+                        Err(err) => {
+                            warn!(%client_id, error = ?err, "Malformed message");
+                            return MsgReading::Stop;
+                        }
+                    };
+                    let err = Msg::new(client_id, msg.id, MSG_ERR, &msg.name)
+                        .with_str_body(Error::RateLimited.description());
+                    if let Ok(mut state) = state.lock() {
+                        // Answers to server's requests are not limited
+                        if !Server::resolve_answer(&mut state, &msg) {
+                            state.push_to(client_id, Arc::new(err.to_bytes()));
+                        }
+                    }
+                    return MsgReading::Continue;
+                }
+            }
+
+            match Server::handle_message(&client_id, state.clone(), msg, ctx.clone()) {
+                Ok(_) => MsgReading::Continue,
+                Err(Error::Unauthorized) => {
+                    reason = DisconnectReason::Unauthorized;
+                    MsgReading::Stop
+                }
+                Err(_) => MsgReading::Stop,
+            }
+        });
+        if end == ReadEnd::Closed {
+            reason = DisconnectReason::Closed;
+        }
+
+        // Handle client disconnecting
+        let mut state = match state.lock() {
+            Ok(s) => s,
+            Err(_) => return Err(Error::Mutex),
+        };
+
+        let maybe_client_index = state.clients.iter().position(|c| c.id == client_id);
+        let removed = maybe_client_index.map(|i| state.clients.remove(i));
+        state.stats.by_client.remove(client_id);
+
+        // Drop requests waiting for answers from this client
+        state.handlers.retain(|h| match (&h.ans, &h.client_id) {
+            (Some(_), Some(id)) => id != client_id,
+            _ => true,
+        });
+
+        let hooks = state.on_disconnect.clone();
+        drop(state);
+        if let Some(client) = removed {
+            // The server may have closed the connection itself
+            let reason = client.closing.unwrap_or(reason);
+            info!(%client_id, client_name = ?client.name, reason = ?reason, "Client disconnected");
+            let info = client.info();
+            for hook in hooks.iter() {
+                hook(&info, reason, ctx.clone());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Find message handler and call it.
+    fn handle_message(
+        client_id: &str,
+        state: SharedState<T>,
+        msg_buff: &[u8],
+        ctx: Arc<Mutex<T>>,
+    ) -> Result<(), Error> {
+        let mut msg = match Msg::from_bytes(msg_buff, client_id) {
+            Ok(msg) => msg,
+            Err(err) => {
+                warn!(%client_id, error = ?err, "Malformed message");
+                return Err(err);
+            }
+        };
+        let state_clone = state.clone();
         let mut locked_state = match state.lock() {
             Ok(s) => s,
             Err(_) => return Err(Error::Mutex),
         };
 
+        // Any message proves the client is alive
+        locked_state.stats.msgs_in += 1;
+        locked_state.stats.bytes_in += msg_buff.len() as u64;
+        if let Some(c) = locked_state.clients.iter_mut().find(|c| c.id == client_id) {
+            c.heartbeat.seen();
+        }
+
+        // Only handshake is allowed before authentication
+        let is_authenticated = |state: &State<T>| {
+            state.clients.iter().any(|c| c.id == client_id && c.authenticated)
+        };
+        if !is_authenticated(&locked_state) && msg.name != "$handshake" {
+            warn!(%client_id, msg_name = %msg.name, "Message before authentication");
+            return Err(Error::Unauthorized);
+        }
+
+        // Set client name
+        if msg.name == "$handshake" {
+            let ans = Server::handle_handshake(&mut locked_state, &msg);
+            if ans.err {
+                let reason = String::from_utf8_lossy(ans.body.as_deref().unwrap_or_default());
+                warn!(%client_id, reason = %reason, "Handshake failed");
+            } else {
+                let client_name = locked_state.client(client_id).and_then(|c| c.name.clone());
+                info!(%client_id, client_name = ?client_name, "Handshake");
+            }
+            locked_state.push_to(client_id, Arc::new(ans.to_bytes()));
+            if !is_authenticated(&locked_state) {
+                return Err(Error::Unauthorized);
+            }
+
+            let info = locked_state.clients.iter().find(|c| c.id == client_id).map(|c| c.info());
+            let hooks = locked_state.on_handshake.clone();
+            drop(locked_state);
+            if let (false, Some(info)) = (ans.err, info) {
+                for hook in hooks.iter() {
+                    hook(&info, ctx.clone());
+                }
+            }
+            return Ok(());
+        }
+
+        {
+            let state = &mut *locked_state;
+            let client = state.clients.iter().find(|c| c.id == client_id);
+            let client_name = client.and_then(|c| c.name.as_deref());
+            let msg_name = Server::stats_name(state, &msg);
+            state.stats.received(client_id, client_name, msg_name, msg_buff.len());
+        }
+
+        // Answers to server's requests never reach handlers or layers
+        if Server::resolve_answer(&mut locked_state, &msg) {
+            return Ok(());
+        }
+
+        // Control messages never reach layers and handlers
+        if Msg::is_reserved(&msg.name) {
+            Server::handle_system(&mut locked_state, &msg);
+            return Ok(());
+        }
+
+        // Pass message through middleware
+        let layers = locked_state.layers.clone();
+        if !layers.is_empty() {
+            drop(locked_state);
+            for layer in layers.iter() {
+                let (msg_id, msg_name, is_req) = (msg.id, msg.name.clone(), msg.req);
+                let mut ans = Msg::new(client_id, msg_id, 0, &msg_name);
+                match layer.before(msg, &state_clone, &ctx) {
+                    Flow::Next(m) => {
+                        msg = m;
+                        continue;
+                    }
+                    Flow::Answer(_) if !is_req => return Ok(()),
+                    Flow::Answer(body) => ans.body = body,
+                    Flow::Reject(reason) => {
+                        debug!(%client_id, msg_id, msg_name = %msg_name, %reason, "Rejected by layer");
+                        ans.err = true;
+                        ans.body = Some(reason.into_bytes());
+                    }
+                }
+                if let Ok(mut locked_state) = state.lock() {
+                    locked_state.push_to(client_id, Arc::new(ans.to_bytes()));
+                }
+                return Ok(());
+            }
+            locked_state = match state.lock() {
+                Ok(s) => s,
+                Err(_) => return Err(Error::Mutex),
+            };
+        }
+
+        // Message for another client
+        if msg.peer.is_some() {
+            Server::route(&mut locked_state, msg);
+            return Ok(());
+        }
+
+        // Request served by another client
+        if msg.req {
+            let provider = locked_state
+                .clients
+                .iter()
+                .find(|c| c.id != client_id && c.provides.contains(&msg.name))
+                .map(|c| c.id.clone());
+            if let Some(provider_id) = provider {
+                let requester = locked_state
+                    .clients
+                    .iter()
+                    .find(|c| c.id == client_id)
+                    .and_then(|c| c.name.clone())
+                    .unwrap_or_else(|| client_id.to_string());
+                Server::proxy(state_clone, msg, provider_id, requester);
+                return Ok(());
+            }
+        }
+
         // Find handler
-        for h in locked_state.handlers.iter_mut() {
-            let mut matched = true;
+        let client_name = match locked_state.clients.iter().find(|c| c.id == client_id) {
+            Some(c) => c.name.clone(),
+            None => None,
+        };
+        debug!(%client_id, msg_id = msg.id, msg_name = %msg.name, req = msg.req, "Dispatch");
+        for h in locked_state.handlers.iter_mut().filter(|h| h.ans.is_none()) {
+            let mut matched = h.msg_name.matches(&msg.name);
             if let Some(ref msg_id) = h.msg_id {
                 matched = matched && *msg_id == msg.id;
             }
-            if let Some(ref msg_name) = h.msg_name {
-                matched = matched && *msg_name == msg.name;
+            if let Some(ref cli_id) = h.client_id {
+                matched = matched && *cli_id == client_id;
             }
-            if !h.client_name.is_none() {
-                matched = matched && match h.client_id {
-                    Some(ref cli_id) => *cli_id == client_id,
+            if !matches!(h.client_name, Pattern::Any) {
+                matched = matched && match client_name {
+                    Some(ref name) => h.client_name.matches(name),
                     None => false,
                 };
             }
             if matched {
-                let mut func = h.func;
+                h.calls += 1;
                 if h.once {
-                    if !h.called {
-                        Server::call_handler(func, msg.clone(), state_clone.clone(), ctx.clone());
-                        h.called = true
+                    if h.called {
+                        continue;
                     }
-                } else {
-                    Server::call_handler(h.func, msg.clone(), state_clone.clone(), ctx.clone());
+                    h.called = true
+                }
+                if let Some(func) = h.func {
+                    Server::call_handler(func, msg.clone(), state_clone.clone(), ctx.clone(), layers.clone());
                 }
             }
         }
+        locked_state.handlers.retain(|h| !(h.once && h.called));
 
         Ok(())
     }
 
+    /// Name the message is counted under, names without handler or pending request share one label.
+    fn stats_name<'a>(state: &State<T>, msg: &'a Msg) -> &'a str {
+        let known = match Msg::is_reserved(&msg.name) {
+            true => SYSTEM_NAMES.contains(&msg.name.as_str()),
+            false => state.handlers.iter().any(|h| match h.ans {
+                Some(_) => !msg.req && h.msg_id == Some(msg.id) && h.client_id.as_deref() == Some(msg.client.as_str()),
+                None => h.func.is_some() && h.msg_name.matches(&msg.name),
+            }),
+        };
+        match known {
+            true => &msg.name,
+            false => stats::OTHER_NAME,
+        }
+    }
+
+    /// Pass the answer to the request waiting for it, returns false if none waits.
+    fn resolve_answer(state: &mut State<T>, msg: &Msg) -> bool {
+        if msg.req {
+            return false;
+        }
+        let pending = state.handlers.iter().position(|h| {
+            h.ans.is_some()
+                && h.msg_id == Some(msg.id)
+                && h.client_id.as_deref() == Some(msg.client.as_str())
+                && h.msg_name.matches(&msg.name)
+        });
+        let handler = match pending {
+            Some(i) => state.handlers.remove(i),
+            None => return false,
+        };
+
+        let ans = match msg.err {
+            true => Err(Error::Remote(String::from_utf8_lossy(msg.body.as_deref().unwrap_or_default()).to_string())),
+            false => Ok(msg.body.clone()),
+        };
+        if let Some(tx) = handler.ans {
+            tx.send(ans).unwrap_or(());
+        }
+        true
+    }
+
+    /// Forward message to its peer or answer the sender with error.
+    fn route(state: &mut State<T>, mut msg: Msg) {
+        let target = msg.peer.take().unwrap_or_default();
+        let sender_i = match state.clients.iter().position(|c| c.id == msg.client) {
+            Some(i) => i,
+            None => return,
+        };
+
+        match state.find_client(&target) {
+            Some(i) => {
+                let sender = state.clients[sender_i].name.clone();
+                msg.peer = Some(sender.unwrap_or_else(|| msg.client.clone()));
+                msg.req = false;
+                state.push(i, Arc::new(msg.to_bytes()));
+            }
+            None => {
+                debug!(client_id = %msg.client, msg_id = msg.id, peer = %target, "Peer not found");
+                let err = Msg::new(&msg.client, msg.id, MSG_ERR, &msg.name)
+                    .with_peer(&target)
+                    .with_str_body(Error::ClientNotFound.description());
+                state.push(sender_i, Arc::new(err.to_bytes()));
+            }
+        }
+    }
+
+    /// Encode message sent by the server, traced if sent from within a traced handler.
+    fn outgoing(msg_name: &str, payload: Payload) -> Result<Frame, Error> {
+        Server::<T>::check_outgoing(msg_name)?;
+        let msg_id = utils::bid_to_u128(&utils::bid());
+        let mut msg = Msg::new("", msg_id, 0, msg_name).with_payload(payload);
+        msg.trace = TraceCtx::current().map(|t| t.child());
+        Ok(Arc::new(msg.to_bytes()))
+    }
+
+    /// Check name of the message sent by the server, `$` names are left to control messages.
+    fn check_outgoing(msg_name: &str) -> Result<(), Error> {
+        if Msg::is_reserved(msg_name) {
+            return Err(Error::Reserved(msg_name.to_string()));
+        }
+        Msg::check_name(msg_name)
+    }
+
+    /// Forward request to the client providing it and relay the answer back.
+    fn proxy(state: SharedState<T>, msg: Msg, provider_id: String, requester: String) {
+        thread::spawn(move || {
+            let fwd_id = utils::bid_to_u128(&utils::bid());
+            let mut fwd = Msg::new("", fwd_id, MSG_REQ, &msg.name).with_peer(&requester);
+            fwd.body = msg.body.clone();
+            fwd.headers = msg.headers.clone();
+            fwd.trace = msg.trace.map(|t| t.child());
+
+            let mut ans = Msg::new(&msg.client, msg.id, 0, &msg.name);
+            match Server::request(&state, &provider_id, fwd) {
+                Ok(body) => ans.body = body,
+                Err(err) => {
+                    ans.err = true;
+                    ans.body = Some(Vec::from(err.description()));
+                }
+            }
+
+            let mut state = match state.lock() {
+                Ok(s) => s,
+                Err(_) => return,
+            };
+            if let Some(i) = state.clients.iter().position(|c| c.id == msg.client) {
+                state.push(i, Arc::new(ans.to_bytes()));
+            }
+        });
+    }
+
     /// Call message handler in separated thread.
-    fn call_handler(h: HandlerFunc<T>, msg: Msg, state: SharedState<T>, ctx: Arc<Mutex<T>>) {
+    fn call_handler(
+        h: HandlerFunc<T>,
+        msg: Msg,
+        state: SharedState<T>,
+        ctx: Arc<Mutex<T>>,
+        layers: Vec<Arc<dyn Layer<T>>>,
+    ) {
         let is_req = msg.req;
         let msg_id = msg.id;
         let msg_name = msg.name.clone();
         let msg_client = msg.client.clone();
 
         thread::spawn(move || {
-            let ans = (h)(msg, state.clone(), ctx);
+            // Sends made by the handler continue the trace of the message
+            let trace = msg.trace.map(|t| t.child());
+            let _trace_guard = trace.map(TraceCtx::enter);
+            let span = info_span!(
+                "handler",
+                client_id = %msg_client,
+                msg_id,
+                msg_name = %msg_name,
+                trace_id = field::Empty,
+                span_id = field::Empty,
+                parent_id = field::Empty,
+            );
+            if let Some(trace) = trace {
+                trace.record(&span);
+            }
+            let _span_guard = span.enter();
+
+            let msg_copy = if layers.is_empty() { None } else { Some(msg.clone()) };
+            let started = Instant::now();
+            let handled = panic::catch_unwind(AssertUnwindSafe(|| (h)(msg, state.clone(), ctx)));
+            let elapsed = started.elapsed();
+            let (mut ans, failed) = match handled {
+                Ok(ans) => (ans, false),
+                Err(_) => {
+                    error!(client_id = %msg_client, msg_id, msg_name = %msg_name, "Handler panicked");
+                    (None, true)
+                }
+            };
+            if let Some(msg) = msg_copy {
+                for layer in layers.iter().rev() {
+                    layer.after(&msg, &mut ans);
+                }
+            }
+
+            let mut locked_state = match state.lock() {
+                Ok(s) => s,
+                Err(_) => return,
+            };
+            locked_state.stats.handled(&msg_client, &msg_name, elapsed);
+            if let Some(threshold) = locked_state.config.slow_handler {
+                if elapsed > threshold {
+                    warn!(
+                        client_id = %msg_client,
+                        msg_id,
+                        msg_name = %msg_name,
+                        elapsed = ?elapsed,
+                        "Slow handler"
+                    );
+                }
+            }
             if !is_req {
                 return;
             }
 
-            let locked_state = state.lock().unwrap();
-            let mut client = locked_state.clients.iter().find(|c| c.id == msg_client);
-            if let Some(ref mut client) = client {
-                let mut stream = client.stream.try_clone().unwrap();
-                Msg::write(
-                    &mut stream,
-                    &utils::u128_to_bytes(msg_id),
-                    &[MSG_WITH_BODY],
-                    msg_name.as_bytes(),
-                    &ans,
-                );
-            }
+            let frame = if failed {
+                let err = Msg::new(&msg_client, msg_id, MSG_ERR, &msg_name)
+                    .with_str_body("Handler failed.");
+                Arc::new(err.to_bytes())
+            } else {
+                let meta = if ans.is_none() { 0u8 } else { MSG_WITH_BODY };
+                Arc::new(Msg::raw(msg_id, meta, &msg_name, ans))
+            };
+            locked_state.push_to(&msg_client, frame);
         });
     }
 
     /// Open socket.
-    fn open_sock(path: &str) -> Result<UnixListener, Error> {
-        match UnixListener::bind(path) {
-            Ok(l) => Ok(l),
-            Err(err) => {
-                // Socket exists -> remove it and open new
-                if err.kind() == io::ErrorKind::AddrInUse {
-                    fs::remove_file(path)?;
-                    Ok(UnixListener::bind(path)?)
-                } else {
-                    Err(Error::from(err))
+    /// Returns the lock file (if configured) which must be kept open while listening.
+    fn open_sock(endpoint: &Endpoint, config: &Config) -> Result<(UnixListener, Option<fs::File>), Error> {
+        let path = match endpoint {
+            Endpoint::Unix(path) => path,
+            _ => return Ok((endpoint.bind_abstract()?, None)),
+        };
+
+        // Only one server can hold the lock
+        let lock = if config.lock_file {
+            let mut lock_path = path.clone().into_os_string();
+            lock_path.push(".lock");
+            Some(Server::<T>::lock_sock(Path::new(&lock_path))?)
+        } else {
+            None
+        };
+
+        let listener = match UnixListener::bind(path) {
+            Ok(l) => l,
+            Err(ref err) if err.kind() == io::ErrorKind::AddrInUse => {
+                let in_use = |reason: &str| {
+                    let reason = format!("{}: {}", reason, path.display());
+                    Err(Error::from(io::Error::new(io::ErrorKind::AddrInUse, reason)))
+                };
+                if config.existing_socket == ExistingSocket::Fail {
+                    return in_use("Socket file exists");
                 }
+                if !fs::symlink_metadata(path)?.file_type().is_socket() {
+                    return in_use("Not a socket");
+                }
+                // Remove the socket only if nobody listens on it
+                match UnixStream::connect(path) {
+                    Err(ref err) if err.kind() == io::ErrorKind::ConnectionRefused => (),
+                    _ => return in_use("Socket is used by another server"),
+                }
+                warn!(path = %path.display(), "Replacing stale socket");
+                fs::remove_file(path)?;
+                UnixListener::bind(path)?
             }
+            Err(err) => return Err(Error::from(err)),
+        };
+
+        if let Some(mode) = config.socket_mode {
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        }
+        let (uid, gid) = config.socket_owner;
+        if uid.is_some() || gid.is_some() {
+            unix_fs::chown(path, uid, gid)?;
+        }
+
+        Ok((listener, lock))
+    }
+
+    /// Take exclusive lock of the file without waiting.
+    fn lock_sock(path: &Path) -> Result<fs::File, Error> {
+        let file = fs::OpenOptions::new().create(true).truncate(false).write(true).mode(0o600).open(path)?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let reason = format!("Lock is held by another server: {}", path.display());
+            return Err(Error::from(io::Error::new(io::ErrorKind::AddrInUse, reason)));
         }
+        Ok(file)
     }
 
     /// Subscribes on some message.
@@ -399,69 +1527,260 @@ impl<T: Send + Sync + 'static> Server<T> {
             Err(_) => return Err(Error::Mutex),
         };
 
-        let client_id = match client_name {
-            ClientName::Is(client_name) => {
-                let maybe_client = state.clients.iter().find(|c| match c.name {
-                    Some(ref name) => name == client_name,
-                    None => false,
-                });
-                match maybe_client {
-                    Some(client) => Some(client.id.clone()),
-                    None => None,
-                }
-            }
-            ClientName::Any => None,
-        };
-
-        let client_name = match client_name {
-            ClientName::Is(client_name) => Some(client_name.to_string()),
-            ClientName::Any => None,
-        };
-
-        let msg_name = match msg_name {
-            MsgName::Any => None,
-            MsgName::Is(val) => Some(val.to_string()),
-        };
+        let client_name = client_name.to_pattern()?;
+        let msg_name = msg_name.to_pattern()?;
+        if msg_name.is_reserved() {
+            return Err(Error::Reserved(msg_name.to_string()));
+        }
 
         state.handlers.push(Handler {
-            func: h,
-            once: once,
+            func: Some(h),
+            ans: None,
+            once,
             called: false,
             msg_id: None,
-            msg_name: msg_name,
-            client_id: client_id,
-            client_name: client_name,
+            msg_name,
+            client_id: None,
+            client_name,
+            calls: 0,
         });
 
         Ok(())
     }
 
-    /// Handle handshake.
-    fn handle_handshake(msg: Msg, state: SharedState<T>, _ctx: Arc<Mutex<T>>) -> Option<Vec<u8>> {
+    /// Handle handshake, returns the answer with client's id and name.
+    fn handle_handshake(state: &mut State<T>, msg: &Msg) -> Msg {
+        let mut ans = Msg::new(&msg.client, msg.id, 0, &msg.name);
+        let client_id: &str = &msg.client;
+        let is_taken = |state: &State<T>, name: &str| {
+            state.clients.iter().any(|c| c.id != client_id && c.name.as_deref() == Some(name))
+        };
+
+        // Name and credential can't be changed later
+        if state.client(client_id).is_some_and(|c| c.handshaken) {
+            ans.err = true;
+            ans.body = Some(Vec::from("Handshake is already done."));
+            return ans;
+        }
+
+        // Check credential
+        let fields = utils::unpack(msg.body.as_ref().map_or(&[], |b| b.as_slice()));
+        let name = fields.first().map(|n| String::from_utf8_lossy(n).to_string()).unwrap_or_default();
+        let credential = fields.get(1).map_or(&[][..], |c| c.as_slice());
+        let verified = match state.clients.iter_mut().find(|c| c.id == client_id) {
+            Some(client) => {
+                client.authenticated = state.config.auth.verify(&name, credential, &client.challenge);
+                client.authenticated
+            }
+            None => false,
+        };
+        if !verified {
+            ans.err = true;
+            ans.body = Some(Vec::from(Error::Unauthorized.description()));
+            return ans;
+        }
+
         // Update client name
-        if let Some(ref body) = msg.body {
-            let mut state = state.lock().unwrap();
-            let new_name = String::from_utf8_lossy(body).to_string();
-            let client_id: &str = &msg.client;
+        let mut assigned_name = String::new();
+        if !name.is_empty() {
+            let mut new_name = name;
+            if is_taken(state, &new_name) {
+                match state.config.name_collision {
+                    NameCollision::Reject => {
+                        ans.err = true;
+                        ans.body = Some(Vec::from(format!("Name {:?} is taken.", new_name)));
+                        return ans;
+                    }
+                    NameCollision::Evict => {
+                        for c in state.clients.iter_mut() {
+                            if c.id != client_id && c.name.as_ref() == Some(&new_name) {
+                                c.name = None;
+                                c.closing = Some(DisconnectReason::Evicted);
+                                c.stream.shutdown(Shutdown::Both).unwrap_or(());
+                            }
+                        }
+                    }
+                    NameCollision::Suffix => {
+                        let mut n = 2;
+                        while is_taken(state, &format!("{}-{}", new_name, n)) {
+                            n += 1;
+                        }
+                        new_name = format!("{}-{}", new_name, n);
+                    }
+                }
+            }
+
+            // Name must fit in the peer field of routed messages, suffix included
+            if let Err(err) = Msg::check_name(&new_name) {
+                ans.err = true;
+                ans.body = Some(Vec::from(err.description()));
+                return ans;
+            }
+
             {
                 let maybe_client = state.clients.iter_mut().find(|c| c.id == client_id);
                 if let Some(client) = maybe_client {
                     client.name = Some(new_name.clone());
                 }
             }
+            assigned_name = new_name;
+        }
+        if let Some(client) = state.clients.iter_mut().find(|c| c.id == client_id) {
+            client.handshaken = true;
+        }
 
-            // Update handlers
-            for handler in state.handlers.iter_mut() {
-                let matched = match handler.client_name {
-                    Some(ref name) => *name == new_name,
-                    None => false,
+        ans.body = Some(utils::pack(&[client_id.as_bytes(), assigned_name.as_bytes()]));
+        ans
+    }
+
+    /// Handle control message of the client.
+    fn handle_system(state: &mut State<T>, msg: &Msg) {
+        let client_id: &str = &msg.client;
+        let arg = msg.body.as_ref().map(|b| String::from_utf8_lossy(b).to_string());
+        let client = state.clients.iter_mut().find(|c| c.id == client_id);
+        match (msg.name.as_str(), client, arg) {
+            ("$ping", _, _) => {
+                let pong = Msg::raw(msg.id, 0, "$pong", None);
+                state.push_to(client_id, Arc::new(pong));
+            }
+            ("$pong", Some(client), _) => client.heartbeat.pong(msg.id),
+            ("$join", Some(client), Some(topic)) if !client.topics.contains(&topic) => {
+                client.topics.push(topic);
+            }
+            ("$leave", Some(client), Some(topic)) => client.topics.retain(|t| *t != topic),
+            ("$provide", Some(client), Some(req_name)) if !client.provides.contains(&req_name) => {
+                client.provides.push(req_name);
+            }
+            ("$pong", _, _) | ("$join", _, _) | ("$leave", _, _) | ("$provide", _, _) => (),
+            _ if msg.req => {
+                let ans = match Server::handle_introspect(state, msg) {
+                    Some(ans) => ans,
+                    None => Msg::new(client_id, msg.id, MSG_ERR, &msg.name)
+                        .with_str_body(Error::Reserved(msg.name.clone()).description()),
                 };
-                if matched {
-                    handler.client_id = Some(client_id.to_string());
-                }
+                state.push_to(client_id, Arc::new(ans.to_bytes()));
             }
+            _ => (),
         }
-        Some(Vec::from(msg.client))
+    }
+
+    /// Answer introspection request, returns None if the name is not known.
+    fn handle_introspect(state: &State<T>, msg: &Msg) -> Option<Msg> {
+        let body = match msg.name.as_str() {
+            "$clients" => Server::describe_clients(state),
+            "$handlers" => Server::describe_handlers(state),
+            "$stats" => Server::describe_stats(state),
+            "$info" => Server::describe_info(state),
+            _ => return None,
+        };
+
+        let mut ans = Msg::new(&msg.client, msg.id, 0, &msg.name);
+        let allowed = match (state.config.introspect, state.client(&msg.client)) {
+            (Some(allow), Some(client)) => allow(client),
+            _ => false,
+        };
+        if allowed {
+            ans.body = Some(body.into_bytes());
+        } else {
+            ans.err = true;
+            ans.body = Some(Vec::from(Error::Forbidden.description()));
+        }
+        Some(ans)
+    }
+
+    /// JSON list of connected clients.
+    fn describe_clients(state: &State<T>) -> String {
+        let clients: Vec<String> = state
+            .clients
+            .iter()
+            .map(|c| {
+                let list = |items: &[String]| {
+                    let items: Vec<String> = items.iter().map(|i| utils::json_str(i)).collect();
+                    format!("[{}]", items.join(","))
+                };
+                let rtt = match c.heartbeat.rtt {
+                    Some(rtt) => format!("{:.3}", rtt.as_secs_f64() * 1000.0),
+                    None => "null".to_string(),
+                };
+                format!(
+                    "{{\"id\":{},\"name\":{},\"transport\":{},\"local_addr\":{},\"remote_addr\":{},\
+                    \"uid\":{},\"pid\":{},\"cert_subject\":{},\"connected_at\":{},\"authenticated\":{},\"topics\":{},\
+                    \"provides\":{},\"rtt_ms\":{},\"queued\":{},\"dropped\":{}}}",
+                    utils::json_str(&c.id),
+                    utils::json_opt(c.name.as_deref()),
+                    utils::json_str(&format!("{:?}", c.peer.transport).to_lowercase()),
+                    utils::json_opt(c.peer.local_addr.as_deref()),
+                    utils::json_opt(c.peer.remote_addr.as_deref()),
+                    c.peer.cred.map_or("null".to_string(), |cred| cred.uid.to_string()),
+                    c.peer.cred.map_or("null".to_string(), |cred| cred.pid.to_string()),
+                    utils::json_opt(c.peer.cert_subject.as_deref()),
+                    utils::unix_secs(c.peer.connected_at),
+                    c.authenticated,
+                    list(&c.topics),
+                    list(&c.provides),
+                    rtt,
+                    c.outbox.len(),
+                    c.outbox.dropped(),
+                )
+            })
+            .collect();
+        format!("[{}]", clients.join(","))
+    }
+
+    /// JSON list of registered handlers (pending requests are skipped).
+    fn describe_handlers(state: &State<T>) -> String {
+        let handlers: Vec<String> = state
+            .handlers
+            .iter()
+            .filter(|h| h.func.is_some())
+            .map(|h| {
+                format!(
+                    "{{\"msg\":{},\"client\":{},\"once\":{},\"calls\":{}}}",
+                    utils::json_str(&h.msg_name.to_string()),
+                    utils::json_str(&h.client_name.to_string()),
+                    h.once,
+                    h.calls,
+                )
+            })
+            .collect();
+        format!("[{}]", handlers.join(","))
+    }
+
+    /// JSON object with server counters.
+    fn describe_stats(state: &State<T>) -> String {
+        let stats = &state.stats;
+        format!(
+            "{{\"clients\":{},\"handlers\":{},\"connections\":{},\"msgs_in\":{},\"bytes_in\":{},\
+            \"msgs_out\":{},\"bytes_out\":{},\"dropped\":{}}}",
+            state.clients.len(),
+            state.handlers.iter().filter(|h| h.func.is_some()).count(),
+            stats.connections,
+            stats.msgs_in,
+            stats.bytes_in,
+            stats.msgs_out,
+            stats.bytes_out,
+            stats.dropped,
+        )
+    }
+
+    /// JSON object with server version and settings.
+    fn describe_info(state: &State<T>) -> String {
+        let auth = match state.config.auth {
+            Auth::None => "none",
+            Auth::Token(_) => "token",
+            Auth::Hmac(_) => "hmac",
+            Auth::Custom(_) => "custom",
+        };
+        let uptime = state.stats.started_at.elapsed().map(|d| d.as_secs()).unwrap_or(0);
+        format!(
+            "{{\"name\":{},\"version\":{},\"pid\":{},\"started_at\":{},\"uptime\":{},\"auth\":{}}}",
+            utils::json_str(env!("CARGO_PKG_NAME")),
+            utils::json_str(env!("CARGO_PKG_VERSION")),
+            ::std::process::id(),
+            utils::unix_secs(state.stats.started_at),
+            uptime,
+            utils::json_str(auth),
+        )
     }
 }
 
@@ -470,24 +1789,606 @@ impl<T: Send + Sync + 'static> Server<T> {
 // -----------------------------
 #[cfg(test)]
 mod tests {
-    use std::sync::{Mutex, Arc};
+    use std::sync::{mpsc, Mutex, Arc};
+    use std::os::unix::net::UnixStream;
+    use std::io::Write;
+    use std::process;
+    use std::time::Duration;
+    use client::{self, Client};
+    use message::{MAX_NAME_LEN, MSG_HEADERS};
     use server::*;
 
+    /// Path of the test socket.
+    fn sock(name: &str) -> String {
+        format!("/tmp/con-test-{}-{}.sock", process::id(), name)
+    }
+
+    /// Listen unix socket of the test in the background.
+    fn serve(mut server: Server<()>, name: &str) -> SharedState<()> {
+        let state = server.state.clone();
+        let address = sock(name);
+        thread::spawn(move || server.listen(&address).unwrap());
+        state
+    }
+
+    /// Connect client to the socket started by `serve()`, handlers may log to its context.
+    fn connect(name: &str, client_name: &str) -> Client<Vec<String>> {
+        let address = sock(name);
+        for _ in 0..100 {
+            if let Ok(client) = Client::connect(&address, Vec::new(), Some(client_name)) {
+                return client;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("cannot connect {}", address);
+    }
+
+    /// Wait until the condition is met or panic.
+    fn wait_until<F: Fn() -> bool>(f: F) {
+        for _ in 0..200 {
+            if f() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("condition not met");
+    }
+
+    fn add_client(server: &Server<()>, name: &str) -> String {
+        let (stream, _) = UnixStream::pair().unwrap();
+        let stream = ConStream::new_unix(stream);
+        let outbox = Outbox::new(stream.try_clone().unwrap(), 8, SlowClientPolicy::DropNewest);
+        let client = ConnectedClient::new(Some(name), stream, outbox);
+        let id = client.id.clone();
+        server.state.lock().unwrap().clients.push(client);
+        id
+    }
+
     #[test]
     fn adding_new_handler() {
         let mut server = Server::new(Arc::new(Mutex::new(())));
 
-        // Only one handler - handshake
+        // No built-in handlers, control messages are handled apart
         {
             let state = server.state.lock().unwrap();
-            assert_eq!(state.handlers.len(), 1);
+            assert_eq!(state.handlers.len(), 0);
         }
 
         // Add handler
         server.on(ClientName::Any, MsgName::Any, |_, _, _| None).unwrap();
         {
             let state = server.state.lock().unwrap();
-            assert_eq!(state.handlers.len(), 2);
+            assert_eq!(state.handlers.len(), 1);
+        }
+
+        // Reserved names
+        match server.on(ClientName::Any, MsgName::Is("$handshake"), |_, _, _| None) {
+            Err(Error::Reserved(_)) => assert!(true),
+            _ => assert!(false),
+        }
+
+        // Name that can't be sent in the frame
+        let long = "x".repeat(MAX_NAME_LEN + 1);
+        assert!(server.on(ClientName::Is(&long), MsgName::Any, |_, _, _| None).is_err());
+        assert!(server.on(ClientName::Any, MsgName::Is(&long), |_, _, _| None).is_err());
+    }
+
+    #[test]
+    fn slow_client_disconnected() {
+        let mut config = Config::default();
+        config.outbox_capacity = 4;
+        config.slow_client_policy = SlowClientPolicy::Disconnect;
+        let mut server = Server::with_config(Arc::new(Mutex::new(Vec::new())), config);
+        server.on_disconnect(|_, reason, ctx| ctx.lock().unwrap().push(reason)).unwrap();
+        let (stream, _peer) = UnixStream::pair().unwrap();
+        Server::handle_client(server.state.clone(), ConStream::new_unix(stream), server.ctx.clone()).unwrap();
+
+        // Peer never reads, writer blocks on the full socket until the queue overflows
+        let body = vec![0u8; 64 * 1024];
+        for _ in 0..200 {
+            if !server.ctx.lock().unwrap().is_empty() {
+                break;
+            }
+            Server::broadcast(&server.state, "flood", Some(body.clone())).unwrap();
+            thread::sleep(Duration::from_millis(10));
         }
+        assert!(server.state.lock().unwrap().clients.is_empty());
+        assert_eq!(*server.ctx.lock().unwrap(), vec![DisconnectReason::SlowClient]);
+    }
+
+    #[test]
+    fn invalid_pattern() {
+        let mut server = Server::new(Arc::new(Mutex::new(())));
+        match server.on(ClientName::Any, MsgName::Regex("job-("), |_, _, _| None) {
+            Err(Error::Pattern(_)) => assert!(true),
+            _ => assert!(false),
+        }
+        assert!(server.on(ClientName::Glob("worker-*"), MsgName::Prefix("job."), |_, _, _| None).is_ok());
+    }
+
+    #[test]
+    fn req_to_unknown_client() {
+        let server = Server::new(Arc::new(Mutex::new(())));
+        match Server::req(&server.state, "nobody", "status", None) {
+            Err(Error::ClientNotFound) => assert!(true),
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    fn client_answers_request_once() {
+        let state = serve(Server::new(Arc::new(Mutex::new(()))), "answer-once");
+        let mut client = connect("answer-once", "agent");
+        client.on(MsgName::Any, |_, _, _| None).unwrap();
+        client.on(MsgName::Is("status"), |_, _, _| Some(Vec::from("ok"))).unwrap();
+
+        assert_eq!(Server::req(&state, "agent", "status", None).unwrap(), Some(Vec::from("ok")));
+        assert_eq!(Server::req(&state, "agent", "other", None).unwrap(), None);
+    }
+
+    #[test]
+    fn answers_bypass_handlers() {
+        let mut server = Server::new(Arc::new(Mutex::new(0u32)));
+        server.on(ClientName::Any, MsgName::Is("secret"), |_, _, ctx| {
+            *ctx.lock().unwrap() += 1;
+            None
+        }).unwrap();
+        server.layer(Deny).unwrap();
+
+        let (stream, mut peer) = UnixStream::pair().unwrap();
+        let stream = ConStream::new_unix(stream);
+        let outbox = Outbox::new(stream.try_clone().unwrap(), 8, SlowClientPolicy::DropNewest);
+        let client = ConnectedClient::new(Some("agent"), stream, outbox);
+        let id = client.id.clone();
+        server.state.lock().unwrap().clients.push(client);
+
+        for (meta, body) in [(MSG_ERR, "Broken."), (0, "ok")] {
+            let state = server.state.clone();
+            let req = thread::spawn(move || Server::req(&state, "agent", "secret", None));
+
+            let mut req_id = 0;
+            Msg::read(&mut peer, |frame| {
+                req_id = Msg::from_bytes(frame, "").unwrap().id;
+                MsgReading::Stop
+            });
+            let ans = Msg::new(&id, req_id, meta, "secret").with_str_body(body).to_bytes();
+            Server::handle_message(&id, server.state.clone(), &ans, server.ctx.clone()).unwrap();
+
+            match req.join().unwrap() {
+                Err(Error::Remote(reason)) => assert_eq!((meta, reason.as_str()), (MSG_ERR, "Broken.")),
+                Ok(ans) => assert_eq!((meta, ans), (0, Some(Vec::from("ok")))),
+                Err(err) => panic!("unexpected {:?}", err),
+            }
+        }
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(*server.ctx.lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn publish_to_topic_members() {
+        let state = serve(Server::new(Arc::new(Mutex::new(()))), "topics");
+        let mut member = connect("topics", "member");
+        let mut other = connect("topics", "other");
+        for client in [&mut member, &mut other] {
+            client.on(MsgName::Is("headline"), |msg, _, ctx| {
+                ctx.lock().unwrap().push(String::from_utf8(msg.body.unwrap()).unwrap());
+                None
+            }).unwrap();
+        }
+        let topics = |name: &str| {
+            let state = state.lock().unwrap();
+            let client = state.clients.iter().find(|c| c.name.as_deref() == Some(name));
+            client.map(|c| c.topics.clone())
+        };
+
+        member.join("news").unwrap();
+        wait_until(|| topics("member") == Some(vec!["news".to_string()]));
+        assert_eq!(Server::publish(&state, "news", "headline", Some(Vec::from("1"))).unwrap(), 1);
+        wait_until(|| *member.ctx.lock().unwrap() == vec!["1"]);
+
+        member.leave("news").unwrap();
+        wait_until(|| topics("member") == Some(Vec::new()));
+        assert_eq!(Server::publish(&state, "news", "headline", Some(Vec::from("2"))).unwrap(), 0);
+
+        member.join("news").unwrap();
+        wait_until(|| topics("member").is_some_and(|t| !t.is_empty()));
+        member.disconnect().unwrap();
+        wait_until(|| topics("member").is_none());
+        assert_eq!(Server::publish(&state, "news", "headline", Some(Vec::from("3"))).unwrap(), 0);
+
+        assert!(other.ctx.lock().unwrap().is_empty());
+        assert_eq!(*member.ctx.lock().unwrap(), vec!["1"]);
+    }
+
+    #[test]
+    fn route_between_clients() {
+        serve(Server::new(Arc::new(Mutex::new(()))), "route");
+        let mut a = connect("route", "a");
+        let mut b = connect("route", "b");
+        for client in [&mut a, &mut b] {
+            client.on(MsgName::Is("hello"), |msg, _, ctx| {
+                let body = String::from_utf8(msg.body.unwrap_or_default()).unwrap();
+                ctx.lock().unwrap().push(format!("{} {:?} {}", msg.err, msg.peer, body));
+                None
+            }).unwrap();
+        }
+
+        // Sender's name is put in the peer field
+        a.send_to("b", "hello", Some(Vec::from("hi"))).unwrap();
+        wait_until(|| !b.ctx.lock().unwrap().is_empty());
+        assert_eq!(*b.ctx.lock().unwrap(), vec!["false Some(\"a\") hi"]);
+
+        // Missing target is reported to the sender
+        a.send_to("nobody", "hello", Some(Vec::from("hi"))).unwrap();
+        wait_until(|| !a.ctx.lock().unwrap().is_empty());
+        assert_eq!(*a.ctx.lock().unwrap(), vec!["true Some(\"nobody\") Client not found."]);
+
+        // Names longer than the frame allows are refused
+        let long = "x".repeat(MAX_NAME_LEN + 1);
+        assert!(matches!(a.send_to(&long, "hello", None), Err(Error::NameTooLong(_))));
+        assert!(matches!(a.send_to("b", &long, None), Err(Error::NameTooLong(_))));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn proxy_to_provider() {
+        let mut config = Config::default();
+        config.req_timeout = Duration::from_secs(60);
+        let state = serve(Server::with_config(Arc::new(Mutex::new(())), config), "proxy");
+        let mut provider = connect("proxy", "b");
+        provider.on(MsgName::Is("render"), |msg, _, _| {
+            Some(format!("<{}>", String::from_utf8(msg.body.unwrap()).unwrap()).into_bytes())
+        }).unwrap();
+        provider.provide("render").unwrap();
+        provider.provide("hang").unwrap();
+        wait_until(|| {
+            let state = state.lock().unwrap();
+            state.clients.iter().any(|c| c.provides.len() == 2)
+        });
+
+        let mut requester = UnixStream::connect(sock("proxy")).unwrap();
+        let read_ans = |stream: &mut UnixStream| {
+            let mut ans = None;
+            Msg::read(stream, |frame| {
+                ans = Some(Msg::from_bytes(frame, "").unwrap());
+                MsgReading::Stop
+            });
+            ans.unwrap()
+        };
+
+        // Answer comes back under the requester's message id
+        requester.write_all(&Msg::new("", 77, MSG_REQ, "render").with_str_body("page").to_bytes()).unwrap();
+        let ans = read_ans(&mut requester);
+        assert_eq!((ans.id, ans.err, ans.body), (77, false, Some(Vec::from("<page>"))));
+
+        // Provider without handler never answers, its disconnect fails the request
+        requester.write_all(&Msg::new("", 78, MSG_REQ, "hang").to_bytes()).unwrap();
+        wait_until(|| state.lock().unwrap().handlers.iter().any(|h| h.ans.is_some()));
+        provider.disconnect().unwrap();
+        let ans = read_ans(&mut requester);
+        assert_eq!((ans.id, ans.err, ans.body), (78, true, Some(Vec::from("Peer disconnected."))));
+    }
+
+    #[test]
+    fn name_collision_suffix() {
+        let server = Server::new(Arc::new(Mutex::new(())));
+        add_client(&server, "agent");
+        add_client(&server, "agent-2");
+        let id = add_client(&server, "");
+        let other_id = add_client(&server, "");
+        let full = "x".repeat(MAX_NAME_LEN);
+        add_client(&server, &full);
+        let long_id = add_client(&server, "");
+
+        let mut state = server.state.lock().unwrap();
+        state.config.name_collision = NameCollision::Suffix;
+        let msg = Msg::new(&id, 1, MSG_REQ, "$handshake").with_body(&utils::pack(&[b"agent"]));
+        let ans = Server::handle_handshake(&mut state, &msg);
+        assert!(!ans.err);
+        assert_eq!(utils::unpack(&ans.body.unwrap())[1], Vec::from("agent-3"));
+
+        // Client can't rename itself with another handshake
+        let msg = Msg::new(&id, 2, MSG_REQ, "$handshake").with_body(&utils::pack(&[b"other"]));
+        assert!(Server::handle_handshake(&mut state, &msg).err);
+        assert_eq!(state.client(&id).unwrap().name.as_deref(), Some("agent-3"));
+
+        state.config.name_collision = NameCollision::Reject;
+        let msg = Msg::new(&other_id, 3, MSG_REQ, "$handshake").with_body(&utils::pack(&[b"agent"]));
+        assert!(Server::handle_handshake(&mut state, &msg).err);
+
+        // Name must fit in the peer field of routed messages, suffix included
+        let long = "x".repeat(MAX_NAME_LEN + 1);
+        let long = Msg::new(&long_id, 4, MSG_REQ, "$handshake").with_body(&utils::pack(&[long.as_bytes()]));
+        assert!(Server::handle_handshake(&mut state, &long).err);
+        state.config.name_collision = NameCollision::Suffix;
+        let long = Msg::new(&long_id, 5, MSG_REQ, "$handshake").with_body(&utils::pack(&[full.as_bytes()]));
+        assert!(Server::handle_handshake(&mut state, &long).err);
+        assert!(state.find_client(&format!("{}-2", full)).is_none());
+    }
+
+    #[test]
+    fn publish_skips_unauthenticated() {
+        let server = Server::new(Arc::new(Mutex::new(())));
+        add_client(&server, "guest");
+        {
+            let mut state = server.state.lock().unwrap();
+            state.clients[0].topics.push("news".to_string());
+            state.clients[0].authenticated = false;
+        }
+        assert_eq!(Server::publish(&server.state, "news", "headline", None).unwrap(), 0);
+
+        server.state.lock().unwrap().clients[0].authenticated = true;
+        assert_eq!(Server::publish(&server.state, "news", "headline", None).unwrap(), 1);
+    }
+
+    #[test]
+    fn too_many_connections() {
+        let mut config = Config::default();
+        config.max_connections = Some(0);
+        config.auth = Auth::Hmac(Vec::from("secret"));
+        serve(Server::with_config(Arc::new(Mutex::new(())), config), "full");
+
+        let anonymous = client::Config::default();
+        let member = client::Config {
+            credentials: auth::Credentials::Hmac(Vec::from("secret")),
+            ..client::Config::default()
+        };
+        for config in vec![anonymous, member] {
+            let mut result = Client::connect_with(&sock("full"), (), Some("guest"), config.clone());
+            for _ in 0..100 {
+                match result {
+                    Err(Error::IO(_)) => thread::sleep(Duration::from_millis(10)),
+                    _ => break,
+                }
+                result = Client::connect_with(&sock("full"), (), Some("guest"), config.clone());
+            }
+            match result {
+                Err(Error::Handshake(reason)) => assert_eq!(reason, "Too many connections."),
+                other => panic!("Unexpected connection result: {:?}", other.err()),
+            }
+        }
+    }
+
+    #[test]
+    fn send_variants() {
+        let server = Server::new(Arc::new(Mutex::new(())));
+        let id = add_client(&server, "agent");
+        add_client(&server, "agent");
+        add_client(&server, "other");
+
+        assert!(Server::send_to_id(&server.state, &id, "ping", None).is_ok());
+        match Server::send_to_id(&server.state, "nobody", "ping", None) {
+            Err(Error::ClientNotFound) => assert!(true),
+            _ => assert!(false),
+        }
+        assert_eq!(Server::send_to_name(&server.state, "agent", "ping", None).unwrap(), 2);
+        assert_eq!(Server::send_where(&server.state, |c| c.id != id, "ping", None).unwrap(), 2);
+        match Server::send(&server.state, "nobody", "ping", None) {
+            Err(Error::ClientNotFound) => assert!(true),
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    fn send_reserved_names() {
+        let server = Server::new(Arc::new(Mutex::new(())));
+        let id = add_client(&server, "agent");
+        server.state.lock().unwrap().clients[0].topics.push("news".to_string());
+
+        let results = vec![
+            Server::broadcast(&server.state, "$ping", None),
+            Server::send(&server.state, "agent", "$ping", None),
+            Server::send_to_id(&server.state, &id, "$ping", None),
+            Server::send_to_name(&server.state, "agent", "$ping", None).map(|_| ()),
+            Server::send_where(&server.state, |_| true, "$ping", None).map(|_| ()),
+            Server::publish(&server.state, "news", "$ping", None).map(|_| ()),
+            Server::req(&server.state, "agent", "$ping", None).map(|_| ()),
+        ];
+        for result in results {
+            match result {
+                Err(Error::Reserved(name)) => assert_eq!(name, "$ping"),
+                other => panic!("Unexpected result: {:?}", other),
+            }
+        }
+        assert_eq!(server.state.lock().unwrap().stats.msgs_out, 0);
+    }
+
+    #[test]
+    fn introspection() {
+        let mut server = Server::new(Arc::new(Mutex::new(())));
+        server.on(ClientName::Any, MsgName::Is("join"), |_, _, _| None).unwrap();
+        let id = add_client(&server, "ops");
+        let mut state = server.state.lock().unwrap();
+        let msg = Msg::new(&id, 1, MSG_REQ, "$handlers");
+
+        // Denied by default
+        assert!(Server::handle_introspect(&state, &msg).unwrap().err);
+
+        state.config.introspect = Some(|c| c.name.as_deref() == Some("ops"));
+        let ans = Server::handle_introspect(&state, &msg).unwrap();
+        assert!(!ans.err);
+        let body = String::from_utf8(ans.body.unwrap()).unwrap();
+        assert!(body.starts_with("[{\"msg\":\"is:join\",\"client\":\"any\""));
+
+        let msg = Msg::new(&id, 2, MSG_REQ, "$clients");
+        let body = Server::handle_introspect(&state, &msg).unwrap().body.unwrap();
+        assert!(String::from_utf8(body).unwrap().contains("\"name\":\"ops\""));
+
+        let msg = Msg::new(&id, 3, MSG_REQ, "$unknown");
+        assert!(Server::handle_introspect(&state, &msg).is_none());
+    }
+
+    #[test]
+    fn system_messages() {
+        let mut server = Server::new(Arc::new(Mutex::new(())));
+        server.on(ClientName::Any, MsgName::Any, |_, _, _| None).unwrap();
+        let id = add_client(&server, "agent");
+
+        let msg = Msg::new(&id, 1, 0, "$join").with_str_body("news").to_bytes();
+        Server::handle_message(&id, server.state.clone(), &msg, server.ctx.clone()).unwrap();
+
+        // Handlers never see control messages, even those matching any name
+        let state = server.state.lock().unwrap();
+        assert_eq!(state.client(&id).unwrap().topics, vec!["news"]);
+        assert_eq!(state.handlers[0].calls, 0);
+    }
+
+    #[test]
+    fn safe_socket_binding() {
+        type S = Server<()>;
+        let dir = ::std::env::temp_dir().join(format!("con-test-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("bus");
+        let endpoint = Endpoint::Unix(path.clone());
+        let mut config = Config::default();
+        config.socket_mode = Some(0o640);
+        config.lock_file = true;
+
+        // Live socket and its lock are not taken over
+        let (listener, lock) = S::open_sock(&endpoint, &config).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o640);
+        match S::open_sock(&endpoint, &config) {
+            Err(Error::IO(ref err)) if err.kind() == io::ErrorKind::AddrInUse => assert!(true),
+            _ => assert!(false),
+        }
+        config.lock_file = false;
+        assert!(S::open_sock(&endpoint, &config).is_err());
+
+        // Stale socket is replaced unless asked to fail
+        drop(listener);
+        drop(lock);
+        config.existing_socket = ExistingSocket::Fail;
+        assert!(S::open_sock(&endpoint, &config).is_err());
+        config.existing_socket = ExistingSocket::ReplaceStale;
+        assert!(S::open_sock(&endpoint, &config).is_ok());
+
+        // Other files are never removed
+        let file = dir.join("file");
+        fs::write(&file, "data").unwrap();
+        assert!(S::open_sock(&Endpoint::Unix(file.clone()), &config).is_err());
+        assert_eq!(fs::read(&file).unwrap(), b"data");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    struct Deny;
+
+    impl<T> Layer<T> for Deny {
+        fn before(&self, msg: Msg, _: &SharedState<T>, _: &Arc<Mutex<T>>) -> Flow {
+            if msg.name == "secret" {
+                return Flow::Reject("Denied.".to_string());
+            }
+            Flow::Next(msg)
+        }
+    }
+
+    #[test]
+    fn layer_rejects_message() {
+        let mut server = Server::new(Arc::new(Mutex::new(())));
+        server.layer(Deny).unwrap();
+
+        let (stream, mut peer) = UnixStream::pair().unwrap();
+        let stream = ConStream::new_unix(stream);
+        let outbox = Outbox::new(stream.try_clone().unwrap(), 8, SlowClientPolicy::DropNewest);
+        let client = ConnectedClient::new(Some("agent"), stream, outbox);
+        let id = client.id.clone();
+        server.state.lock().unwrap().clients.push(client);
+
+        let msg = Msg::new(&id, 7, MSG_REQ, "secret").to_bytes();
+        Server::handle_message(&id, server.state.clone(), &msg, server.ctx.clone()).unwrap();
+
+        let mut ans = None;
+        Msg::read(&mut peer, |frame| {
+            ans = Some(Msg::from_bytes(frame, "").unwrap());
+            MsgReading::Stop
+        });
+        let ans = ans.unwrap();
+        assert!(ans.err && ans.id == 7);
+        assert_eq!(ans.body, Some(Vec::from("Denied.")));
+    }
+
+    #[test]
+    fn stats_by_known_names() {
+        let mut server = Server::new(Arc::new(Mutex::new(())));
+        server.on(ClientName::Any, MsgName::Is("job"), |_, _, _| None).unwrap();
+        let (stream, mut peer) = UnixStream::pair().unwrap();
+        Server::handle_client(server.state.clone(), ConStream::new_unix(stream), server.ctx.clone()).unwrap();
+
+        for (id, name) in ["job", "spam-1", "spam-2", "$spam", "$ping"].iter().enumerate() {
+            peer.write_all(&Msg::raw(id as u128, 0, name, None)).unwrap();
+        }
+        wait_until(|| server.state.lock().unwrap().stats.msgs_in == 5);
+
+        let state = server.state.lock().unwrap();
+        let mut names: Vec<(&str, u64)> = state.stats.by_name.iter().map(|(n, t)| (n.as_str(), t.msgs_in)).collect();
+        names.sort();
+        assert_eq!(names, vec![("$ping", 1), ("job", 1), ("other", 3)]);
+    }
+
+    #[test]
+    fn malformed_frame_disconnects() {
+        let server = Server::new(Arc::new(Mutex::new(())));
+        let (stream, mut peer) = UnixStream::pair().unwrap();
+        Server::handle_client(server.state.clone(), ConStream::new_unix(stream), server.ctx.clone()).unwrap();
+        let id = server.state.lock().unwrap().clients[0].id.clone();
+
+        // Header key longer than the section
+        let mut frame = Msg::raw(1, MSG_HEADERS, "status", None);
+        frame.extend_from_slice(&[0, 0, 0, 1, 200]);
+        peer.write_all(&frame).unwrap();
+
+        wait_until(|| server.state.lock().unwrap().client(&id).is_none());
+    }
+
+    #[test]
+    fn lifecycle_hooks() {
+        let mut server = Server::new(Arc::new(Mutex::new(Vec::new())));
+        server.on_connect(|c, ctx| ctx.lock().unwrap().push(format!("connect {}", c.id))).unwrap();
+        server.on_disconnect(|c, reason, ctx| {
+            ctx.lock().unwrap().push(format!("{:?} {}", reason, c.id));
+        }).unwrap();
+
+        let connect = |server: &Server<Vec<String>>| {
+            let (stream, peer) = UnixStream::pair().unwrap();
+            let stream = ConStream::new_unix(stream);
+            Server::handle_client(server.state.clone(), stream, server.ctx.clone()).unwrap();
+            let id = server.state.lock().unwrap().clients.last().unwrap().id.clone();
+            (id, peer)
+        };
+        let gone = |server: &Server<Vec<String>>, id: &str| server.state.lock().unwrap().client(id).is_none();
+
+        let (kicked, _peer) = connect(&server);
+        Server::disconnect(&server.state, &kicked).unwrap();
+        wait_until(|| gone(&server, &kicked));
+
+        let (closed, peer) = connect(&server);
+        drop(peer);
+        wait_until(|| gone(&server, &closed));
+
+        let events = server.ctx.lock().unwrap().clone();
+        assert_eq!(events, vec![
+            format!("connect {}", kicked),
+            format!("Kicked {}", kicked),
+            format!("connect {}", closed),
+            format!("Closed {}", closed),
+        ]);
+    }
+
+    #[test]
+    fn hooks_run_unlocked() {
+        let mut server = Server::new(Arc::new(Mutex::new(Vec::new())));
+        server.on_connect(|c, ctx| ctx.lock().unwrap().push(c.id.clone())).unwrap();
+
+        // Handlers lock context first and then the state, hooks must not wait for the state
+        let ctx = server.ctx.lock().unwrap();
+        let (state, hook_ctx) = (server.state.clone(), server.ctx.clone());
+        let (stream, _peer) = UnixStream::pair().unwrap();
+        thread::spawn(move || Server::handle_client(state, ConStream::new_unix(stream), hook_ctx).unwrap());
+        thread::sleep(Duration::from_millis(50));
+
+        let (done_tx, done_rx) = mpsc::channel();
+        let state = server.state.clone();
+        thread::spawn(move || done_tx.send(Server::broadcast(&state, "news", None).is_ok()).unwrap());
+        assert_eq!(done_rx.recv_timeout(Duration::from_secs(2)), Ok(true));
+        drop(ctx);
+    }
+}
@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::{Duration, SystemTime};
+
+/// Upper bounds of latency buckets in seconds.
+static LATENCY_BUCKETS: [f64; 14] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Label of messages whose names are not known to the server.
+pub static OTHER_NAME: &str = "other";
+
+/// Server counters.
+#[derive(Debug, Clone)]
+pub struct Stats {
+    /// When the server was created.
+    pub started_at: SystemTime,
+    /// Accepted connections.
+    pub connections: u64,
+    /// Messages received from clients.
+    pub msgs_in: u64,
+    /// Bytes received from clients.
+    pub bytes_in: u64,
+    /// Messages queued for clients.
+    pub msgs_out: u64,
+    /// Bytes queued for clients.
+    pub bytes_out: u64,
+    /// Messages dropped because of full or closed client queues.
+    pub dropped: u64,
+    /// Traffic and handler latency by message name (unknown names under `OTHER_NAME`).
+    pub by_name: HashMap<String, Traffic>,
+    /// Traffic and handler latency by client id (removed on disconnect).
+    pub by_client: HashMap<String, Traffic>,
+}
+
+/// Counters of one message name or client.
+#[derive(Debug, Clone, Default)]
+pub struct Traffic {
+    /// Name of the client (if counted by client).
+    pub name: Option<String>,
+    pub msgs_in: u64,
+    pub bytes_in: u64,
+    pub msgs_out: u64,
+    pub bytes_out: u64,
+    /// Duration of handler calls.
+    pub latency: Histogram,
+}
+
+/// Cumulative histogram of durations.
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    /// Number of observations not greater than each of `LATENCY_BUCKETS`.
+    pub buckets: Vec<u64>,
+    /// Sum of observations in seconds.
+    pub sum: f64,
+    pub count: u64,
+}
+
+impl Histogram {
+    /// Add observation.
+    pub fn observe(&mut self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        for (i, bound) in LATENCY_BUCKETS.iter().enumerate() {
+            if secs <= *bound {
+                self.buckets[i] += 1;
+            }
+        }
+        self.sum += secs;
+        self.count += 1;
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            buckets: vec![0; LATENCY_BUCKETS.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats {
+            started_at: SystemTime::now(),
+            connections: 0,
+            msgs_in: 0,
+            bytes_in: 0,
+            msgs_out: 0,
+            bytes_out: 0,
+            dropped: 0,
+            by_name: HashMap::new(),
+            by_client: HashMap::new(),
+        }
+    }
+}
+
+impl Stats {
+    /// Count message received from the client.
+    pub fn received(&mut self, client_id: &str, client_name: Option<&str>, msg_name: &str, len: usize) {
+        let by_name = self.by_name.entry(msg_name.to_string()).or_default();
+        by_name.msgs_in += 1;
+        by_name.bytes_in += len as u64;
+
+        let by_client = self.by_client.entry(client_id.to_string()).or_default();
+        by_client.msgs_in += 1;
+        by_client.bytes_in += len as u64;
+        if by_client.name.as_deref() != client_name {
+            by_client.name = client_name.map(|n| n.to_string());
+        }
+    }
+
+    /// Count message queued for the client.
+    pub fn sent(&mut self, client_id: &str, len: usize) {
+        self.msgs_out += 1;
+        self.bytes_out += len as u64;
+        if let Some(by_client) = self.by_client.get_mut(client_id) {
+            by_client.msgs_out += 1;
+            by_client.bytes_out += len as u64;
+        }
+    }
+
+    /// Record duration of the handler call.
+    pub fn handled(&mut self, client_id: &str, msg_name: &str, duration: Duration) {
+        if let Some(by_name) = self.by_name.get_mut(msg_name) {
+            by_name.latency.observe(duration);
+        }
+        if let Some(by_client) = self.by_client.get_mut(client_id) {
+            by_client.latency.observe(duration);
+        }
+    }
+
+    /// Render counters in Prometheus text format.
+    pub fn to_prometheus(&self, clients: usize) -> String {
+        let mut out = String::with_capacity(4096);
+        let uptime = self.started_at.elapsed().map(|d| d.as_secs()).unwrap_or(0);
+
+        let totals = [
+            ("con_uptime_seconds", "gauge", "Seconds since the server was created.", uptime),
+            ("con_clients", "gauge", "Connected clients.", clients as u64),
+            ("con_connections_total", "counter", "Accepted connections.", self.connections),
+            ("con_messages_received_total", "counter", "Messages received.", self.msgs_in),
+            ("con_bytes_received_total", "counter", "Bytes received.", self.bytes_in),
+            ("con_messages_sent_total", "counter", "Messages queued for sending.", self.msgs_out),
+            ("con_bytes_sent_total", "counter", "Bytes queued for sending.", self.bytes_out),
+            ("con_messages_dropped_total", "counter", "Messages dropped.", self.dropped),
+        ];
+        for (name, kind, help, val) in totals.iter() {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}\n{} {}", name, help, name, kind, name, val);
+        }
+
+        let mut names: Vec<(String, &Traffic)> = self
+            .by_name
+            .iter()
+            .map(|(name, t)| (format!("name=\"{}\"", escape(name)), t))
+            .collect();
+        names.sort_by(|a, b| a.0.cmp(&b.0));
+        render_traffic(&mut out, "con_name", &names, false);
+
+        let mut clients: Vec<(String, &Traffic)> = self
+            .by_client
+            .iter()
+            .map(|(id, t)| {
+                let name = escape(t.name.as_deref().unwrap_or(""));
+                (format!("client_id=\"{}\",client=\"{}\"", escape(id), name), t)
+            })
+            .collect();
+        clients.sort_by(|a, b| a.0.cmp(&b.0));
+        render_traffic(&mut out, "con_client", &clients, true);
+
+        out
+    }
+}
+
+/// Render labeled counters and latency histograms.
+fn render_traffic(out: &mut String, prefix: &str, items: &[(String, &Traffic)], with_sent: bool) {
+    type Counter = fn(&Traffic) -> u64;
+    let mut counters: Vec<(&str, &str, Counter)> = vec![
+        ("messages_received_total", "Messages received.", |t| t.msgs_in),
+        ("bytes_received_total", "Bytes received.", |t| t.bytes_in),
+    ];
+    if with_sent {
+        counters.push(("messages_sent_total", "Messages queued for sending.", |t| t.msgs_out));
+        counters.push(("bytes_sent_total", "Bytes queued for sending.", |t| t.bytes_out));
+    }
+    for (name, help, counter) in counters {
+        let _ = writeln!(out, "# HELP {}_{} {}\n# TYPE {}_{} counter", prefix, name, help, prefix, name);
+        for (labels, t) in items {
+            let _ = writeln!(out, "{}_{}{{{}}} {}", prefix, name, labels, counter(t));
+        }
+    }
+
+    let name = format!("{}_handler_duration_seconds", prefix);
+    let _ = writeln!(out, "# HELP {} Duration of handler calls.\n# TYPE {} histogram", name, name);
+    for (labels, t) in items {
+        let h = &t.latency;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(h.buckets.iter()) {
+            let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, bound, count);
+        }
+        let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, h.count);
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, h.sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, h.count);
+    }
+}
+
+/// Escape label value.
+fn escape(val: &str) -> String {
+    val.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+// -----------------------------
+// --- --- --- Tests --- --- ---
+// -----------------------------
+#[cfg(test)]
+mod tests {
+    use stats::*;
+
+    #[test]
+    fn histogram() {
+        let mut h = Histogram::default();
+        h.observe(Duration::from_millis(3));
+        h.observe(Duration::from_secs(20));
+        assert_eq!(h.buckets[2], 0);
+        assert_eq!(h.buckets[3], 1);
+        assert_eq!(h.buckets[LATENCY_BUCKETS.len() - 1], 1);
+        assert_eq!(h.count, 2);
+    }
+
+    #[test]
+    fn prometheus() {
+        let mut stats = Stats::default();
+        stats.received("id1", Some("a\"b"), "job", 10);
+        stats.sent("id1", 5);
+        stats.handled("id1", "job", Duration::from_millis(1));
+
+        let text = stats.to_prometheus(1);
+        assert!(text.contains("con_clients 1\n"));
+        assert!(text.contains("con_name_bytes_received_total{name=\"job\"} 10\n"));
+        assert!(text.contains("con_client_messages_sent_total{client_id=\"id1\",client=\"a\\\"b\"} 1\n"));
+        assert!(text.contains("con_name_handler_duration_seconds_bucket{name=\"job\",le=\"0.001\"} 1\n"));
+        assert!(text.contains("con_name_handler_duration_seconds_count{name=\"job\"} 1\n"));
+    }
+}
@@ -1,12 +1,24 @@
 use errors::Error;
+use mem::MemStream;
 use std::io::{self, Read, Write};
 use std::net::{Shutdown, TcpStream};
+use std::os::unix::io::AsRawFd;
 use std::os::unix::net::UnixStream;
+use peer::{PeerCred, PeerInfo, Transport};
+use std::os::unix::net::SocketAddr;
+use std::time::SystemTime;
+use ws::WsStream;
+#[cfg(feature = "tls")]
+use tls::TlsStream;
 
 #[derive(Debug)]
 pub struct ConStream {
     unix: Option<UnixStream>,
     tcp: Option<TcpStream>,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsStream>,
+    ws: Option<WsStream>,
+    mem: Option<MemStream>,
 }
 
 impl ConStream {
@@ -15,6 +27,10 @@ impl ConStream {
         ConStream {
             unix: None,
             tcp: Some(stream),
+            #[cfg(feature = "tls")]
+            tls: None,
+            ws: None,
+            mem: None,
         }
     }
 
@@ -23,22 +39,66 @@ impl ConStream {
         ConStream {
             unix: Some(stream),
             tcp: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+            ws: None,
+            mem: None,
+        }
+    }
+
+    /// Create stream from TlsStream.
+    #[cfg(feature = "tls")]
+    pub fn new_tls(stream: TlsStream) -> ConStream {
+        ConStream {
+            unix: None,
+            tcp: None,
+            tls: Some(stream),
+            ws: None,
+            mem: None,
+        }
+    }
+
+    /// Create stream from upgraded WsStream.
+    pub fn new_ws(stream: WsStream) -> ConStream {
+        ConStream {
+            unix: None,
+            tcp: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+            ws: Some(stream),
+            mem: None,
+        }
+    }
+
+    /// Create stream from in-process MemStream.
+    pub fn new_mem(stream: MemStream) -> ConStream {
+        ConStream {
+            unix: None,
+            tcp: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+            ws: None,
+            mem: Some(stream),
         }
     }
 
     /// Try to clone underlayed streams (if any).
     pub fn try_clone(&self) -> Result<ConStream, Error> {
         if let Some(ref s) = self.unix {
-            return Ok(ConStream {
-                unix: Some(s.try_clone()?),
-                tcp: None,
-            });
+            return Ok(ConStream::new_unix(s.try_clone()?));
         }
         if let Some(ref s) = self.tcp {
-            return Ok(ConStream {
-                unix: None,
-                tcp: Some(s.try_clone()?),
-            });
+            return Ok(ConStream::new_tcp(s.try_clone()?));
+        }
+        #[cfg(feature = "tls")]
+        if let Some(ref s) = self.tls {
+            return Ok(ConStream::new_tls(s.clone()));
+        }
+        if let Some(ref s) = self.ws {
+            return Ok(ConStream::new_ws(s.try_clone()?));
+        }
+        if let Some(ref s) = self.mem {
+            return Ok(ConStream::new_mem(s.try_clone()?));
         }
         Err(Error::Empty)
     }
@@ -53,9 +113,91 @@ impl ConStream {
             s.set_nonblocking(nonblocking)?;
             return Ok(());
         }
+        #[cfg(feature = "tls")]
+        if let Some(ref s) = self.tls {
+            s.set_nonblocking(nonblocking)?;
+            return Ok(());
+        }
+        if let Some(ref s) = self.ws {
+            s.set_nonblocking(nonblocking)?;
+            return Ok(());
+        }
+        if let Some(ref s) = self.mem {
+            s.set_nonblocking(nonblocking)?;
+            return Ok(());
+        }
         Err(Error::Empty)
     }
 
+    /// Credentials of the peer process (unix sockets only).
+    pub fn peer_cred(&self) -> Option<PeerCred> {
+        match self.unix {
+            Some(ref s) => PeerCred::from_fd(s.as_raw_fd()),
+            None => None,
+        }
+    }
+
+    /// Collect information about the other end of connection.
+    pub fn peer_info(&self) -> PeerInfo {
+        let unix_path = |addr: io::Result<SocketAddr>| {
+            addr.ok().and_then(|a| match a.as_pathname() {
+                Some(p) => Some(p.to_string_lossy().to_string()),
+                None => abstract_name(&a).map(|n| format!("@{}", n)),
+            })
+        };
+
+        if let Some(ref s) = self.tcp {
+            return PeerInfo {
+                transport: Transport::Tcp,
+                local_addr: s.local_addr().ok().map(|a| a.to_string()),
+                remote_addr: s.peer_addr().ok().map(|a| a.to_string()),
+                cred: None,
+                cert_subject: None,
+                connected_at: SystemTime::now(),
+            };
+        }
+        #[cfg(feature = "tls")]
+        if let Some(ref s) = self.tls {
+            return PeerInfo {
+                transport: Transport::Tls,
+                local_addr: s.local_addr().ok().map(|a| a.to_string()),
+                remote_addr: s.peer_addr().ok().map(|a| a.to_string()),
+                cred: None,
+                cert_subject: s.subject().map(|s| s.to_string()),
+                connected_at: SystemTime::now(),
+            };
+        }
+        if let Some(ref s) = self.ws {
+            return PeerInfo {
+                transport: Transport::WebSocket,
+                local_addr: s.local_addr().ok().map(|a| a.to_string()),
+                remote_addr: s.peer_addr().ok().map(|a| a.to_string()),
+                cred: None,
+                cert_subject: None,
+                connected_at: SystemTime::now(),
+            };
+        }
+        if let Some(ref s) = self.mem {
+            return PeerInfo {
+                transport: Transport::Memory,
+                local_addr: Some(format!("mem://{}", s.name())),
+                remote_addr: None,
+                cred: None,
+                cert_subject: None,
+                connected_at: SystemTime::now(),
+            };
+        }
+
+        PeerInfo {
+            transport: Transport::Unix,
+            local_addr: self.unix.as_ref().and_then(|s| unix_path(s.local_addr())),
+            remote_addr: self.unix.as_ref().and_then(|s| unix_path(s.peer_addr())),
+            cred: self.peer_cred(),
+            cert_subject: None,
+            connected_at: SystemTime::now(),
+        }
+    }
+
     /// 'shutdown()' impl.
     pub fn shutdown(&self, how: Shutdown) -> Result<(), Error> {
         if let Some(ref s) = self.unix {
@@ -66,10 +208,35 @@ impl ConStream {
             s.shutdown(how)?;
             return Ok(());
         }
+        #[cfg(feature = "tls")]
+        if let Some(ref s) = self.tls {
+            s.shutdown(how)?;
+            return Ok(());
+        }
+        if let Some(ref s) = self.ws {
+            s.shutdown(how)?;
+            return Ok(());
+        }
+        if let Some(ref s) = self.mem {
+            s.shutdown(how)?;
+            return Ok(());
+        }
         Err(Error::Empty)
     }
 }
 
+/// Name of the abstract unix socket.
+#[cfg(target_os = "linux")]
+fn abstract_name(addr: &SocketAddr) -> Option<String> {
+    use std::os::linux::net::SocketAddrExt;
+    addr.as_abstract_name().map(|n| String::from_utf8_lossy(n).to_string())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn abstract_name(_addr: &SocketAddr) -> Option<String> {
+    None
+}
+
 impl Write for ConStream {
     fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
         if let Some(ref mut s) = self.unix {
@@ -78,6 +245,16 @@ impl Write for ConStream {
         if let Some(ref mut s) = self.tcp {
             return s.write(buf);
         }
+        #[cfg(feature = "tls")]
+        if let Some(ref mut s) = self.tls {
+            return s.write(buf);
+        }
+        if let Some(ref mut s) = self.ws {
+            return s.write(buf);
+        }
+        if let Some(ref mut s) = self.mem {
+            return s.write(buf);
+        }
         Ok(0)
     }
 
@@ -88,6 +265,16 @@ impl Write for ConStream {
         if let Some(ref mut s) = self.tcp {
             return s.flush();
         }
+        #[cfg(feature = "tls")]
+        if let Some(ref mut s) = self.tls {
+            return s.flush();
+        }
+        if let Some(ref mut s) = self.ws {
+            return s.flush();
+        }
+        if let Some(ref mut s) = self.mem {
+            return s.flush();
+        }
         Ok(())
     }
 }
@@ -100,6 +287,16 @@ impl Read for ConStream {
         if let Some(ref mut s) = self.tcp {
             return s.read(buf);
         }
+        #[cfg(feature = "tls")]
+        if let Some(ref mut s) = self.tls {
+            return s.read(buf);
+        }
+        if let Some(ref mut s) = self.ws {
+            return s.read(buf);
+        }
+        if let Some(ref mut s) = self.mem {
+            return s.read(buf);
+        }
         Ok(0)
     }
 }
@@ -110,43 +307,15 @@ impl Read for ConStream {
 #[cfg(test)]
 mod tests {
     use std::net::{TcpListener, TcpStream};
-    use std::os::unix::net::{UnixListener, UnixStream};
-    use std::thread;
-    use std::time;
-    use std::fs;
+    use std::os::unix::net::UnixStream;
+    use mem::MemStream;
     use stream::*;
 
-    fn setup_tcp_listener() {
-        thread::spawn(move || {
-            let _listener = TcpListener::bind("127.0.0.1:1234").unwrap();
-            thread::sleep(time::Duration::from_millis(200));
-        });
-        thread::sleep(time::Duration::from_millis(100));
-    }
-
-    fn setup_unix_listener() {
-        thread::spawn(move || {
-            let _listener = match UnixListener::bind("/tmp/con-test.sock") {
-                Ok(l) => l,
-                Err(err) => {
-                    if err.kind() == io::ErrorKind::AddrInUse {
-                        fs::remove_file("/tmp/con-test.sock").unwrap();
-                        UnixListener::bind("/tmp/con-test.sock").unwrap()
-                    } else {
-                        panic!(err);
-                    }
-                }
-            };
-            thread::sleep(time::Duration::from_millis(200));
-        });
-        thread::sleep(time::Duration::from_millis(100));
-    }
-
     #[test]
     fn tcp_creating_and_cloning() {
-        setup_tcp_listener();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
 
-        let tcp_stream = TcpStream::connect("127.0.0.1:1234").unwrap();
+        let tcp_stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
         let stream = ConStream::new_tcp(tcp_stream);
         match stream.tcp {
             Some(_) => assert!(true),
@@ -170,9 +339,7 @@ mod tests {
 
     #[test]
     fn unix_creating_and_cloning() {
-        setup_unix_listener();
-
-        let unix_stream = UnixStream::connect("/tmp/con-test.sock").unwrap();
+        let (unix_stream, _peer) = UnixStream::pair().unwrap();
         let stream = ConStream::new_unix(unix_stream);
         match stream.tcp {
             Some(_) => assert!(false),
@@ -193,4 +360,24 @@ mod tests {
             None => assert!(false),
         }
     }
+
+    #[test]
+    fn unix_peer_info() {
+        let (a, _b) = UnixStream::pair().unwrap();
+        let info = ConStream::new_unix(a).peer_info();
+        assert_eq!(info.transport, Transport::Unix);
+        assert_eq!(info.remote_addr, None);
+        assert_eq!(info.cred.map(|c| c.pid as u32), Some(::std::process::id()));
+    }
+
+    #[test]
+    fn mem_peer_info() {
+        let (a, _b) = MemStream::pair("bus");
+        let stream = ConStream::new_mem(a).try_clone().unwrap();
+        assert!(stream.mem.is_some() && stream.unix.is_none());
+        let info = stream.peer_info();
+        assert_eq!(info.transport, Transport::Memory);
+        assert_eq!(info.local_addr.as_deref(), Some("mem://bus"));
+        assert_eq!(info.origin(), None);
+    }
 }
@@ -0,0 +1,295 @@
+use errors::Error;
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, ClientConnection, Connection, RootCertStore, ServerConfig, ServerConnection};
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/// Max duration of the handshake.
+static HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Load certificates from PEM file.
+pub fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(Error::Tls(format!("No certificates in {}", path.display())));
+    }
+    Ok(certs)
+}
+
+/// Load the first private key from PEM file.
+pub fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>, Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    match rustls_pemfile::private_key(&mut reader)? {
+        Some(key) => Ok(key),
+        None => Err(Error::Tls(format!("No private key in {}", path.display()))),
+    }
+}
+
+/// Server settings from PEM files.
+/// With `client_ca` clients must present certificates signed by it.
+pub fn server_config(cert: &Path, key: &Path, client_ca: Option<&Path>) -> Result<Arc<ServerConfig>, Error> {
+    let builder = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(tls_err)?;
+    let builder = match client_ca {
+        Some(ca) => {
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots(ca)?), provider())
+                .build()
+                .map_err(tls_err)?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder.with_single_cert(load_certs(cert)?, load_key(key)?).map_err(tls_err)?;
+    Ok(Arc::new(config))
+}
+
+/// Client settings trusting certificates signed by `ca`.
+/// `identity` is the certificate and key presented to servers requiring them.
+pub fn client_config(ca: &Path, identity: Option<(&Path, &Path)>) -> Result<Arc<ClientConfig>, Error> {
+    let builder = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(tls_err)?
+        .with_root_certificates(roots(ca)?);
+    let config = match identity {
+        Some((cert, key)) => builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?).map_err(tls_err)?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+/// TLS session over tcp stream shared by reader and writer threads.
+#[derive(Clone)]
+pub struct TlsStream {
+    tcp: Arc<TcpStream>,
+    conn: Arc<Mutex<Connection>>,
+    subject: Option<String>,
+}
+
+impl TlsStream {
+    /// Accept client's session, blocks until the handshake is done.
+    pub fn accept(tcp: TcpStream, config: Arc<ServerConfig>) -> Result<TlsStream, Error> {
+        let conn = ServerConnection::new(config).map_err(tls_err)?;
+        TlsStream::handshake(tcp, conn.into())
+    }
+
+    /// Connect to "host:port", blocks until the handshake is done.
+    pub fn connect(address: &str, config: Arc<ClientConfig>) -> Result<TlsStream, Error> {
+        let host = match address.rfind(':') {
+            Some(i) => address[..i].trim_start_matches('[').trim_end_matches(']'),
+            None => address,
+        };
+        let server_name = ServerName::try_from(host.to_string()).map_err(tls_err)?;
+        let conn = ClientConnection::new(config, server_name).map_err(tls_err)?;
+        TlsStream::handshake(TcpStream::connect(address)?, conn.into())
+    }
+
+    /// Subject of the verified peer certificate, e.g. "CN=client-a".
+    pub fn subject(&self) -> Option<&str> {
+        self.subject.as_deref()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp.local_addr()
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp.peer_addr()
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.tcp.set_nonblocking(nonblocking)
+    }
+
+    /// Shutdown tcp stream without waiting for the session.
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.tcp.shutdown(how)
+    }
+
+    fn handshake(mut tcp: TcpStream, mut conn: Connection) -> Result<TlsStream, Error> {
+        tcp.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut tcp)?;
+        }
+        while conn.wants_write() {
+            conn.write_tls(&mut tcp)?;
+        }
+        tcp.set_read_timeout(None)?;
+
+        let subject = conn
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .and_then(|cert| X509Certificate::from_der(cert).ok())
+            .map(|(_, cert)| cert.subject().to_string());
+
+        Ok(TlsStream {
+            tcp: Arc::new(tcp),
+            conn: Arc::new(Mutex::new(conn)),
+            subject,
+        })
+    }
+
+    fn lock(&self) -> io::Result<MutexGuard<'_, Connection>> {
+        self.conn.lock().map_err(|_| io::Error::other("TLS session is poisoned"))
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.lock()?.reader().read(buf) {
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => (),
+                result => return result,
+            }
+
+            // Wait for data without holding the session, so writers aren't blocked
+            if self.tcp.peek(&mut [0])? == 0 {
+                return Ok(0);
+            }
+
+            let mut conn = self.lock()?;
+            conn.read_tls(&mut &*self.tcp)?;
+            conn.process_new_packets().map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            while conn.wants_write() {
+                conn.write_tls(&mut &*self.tcp)?;
+            }
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut conn = self.lock()?;
+        let n = conn.writer().write(buf)?;
+        while conn.wants_write() {
+            conn.write_tls(&mut &*self.tcp)?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut conn = self.lock()?;
+        conn.writer().flush()?;
+        while conn.wants_write() {
+            conn.write_tls(&mut &*self.tcp)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for TlsStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TlsStream")
+            .field("tcp", &self.tcp)
+            .field("subject", &self.subject)
+            .finish()
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+/// Trusted certificates from PEM file.
+fn roots(ca: &Path) -> Result<RootCertStore, Error> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(ca)? {
+        roots.add(cert).map_err(tls_err)?;
+    }
+    Ok(roots)
+}
+
+fn tls_err<E: fmt::Display>(err: E) -> Error {
+    Error::Tls(err.to_string())
+}
+
+// -----------------------------
+// --- --- --- Tests --- --- ---
+// -----------------------------
+#[cfg(test)]
+mod tests {
+    extern crate rcgen;
+
+    use self::rcgen::{BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+    use client::{self, Client};
+    use message::MsgName;
+    use peer::Transport;
+    use server::{self, ClientName, Server};
+    use std::fs;
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::sync::mpsc;
+    use std::thread;
+    use tls::*;
+
+    /// Write CA, server and client certificates with keys to temp dir.
+    fn write_certs() -> PathBuf {
+        let dir = ::std::env::temp_dir().join(format!("con-tls-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        ca_params.distinguished_name.push(DnType::CommonName, "con test CA");
+        let ca_key = KeyPair::generate().unwrap();
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+
+        for (name, purpose) in [("server", ExtendedKeyUsagePurpose::ServerAuth), ("client-a", ExtendedKeyUsagePurpose::ClientAuth)] {
+            let mut params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+            params.distinguished_name.push(DnType::CommonName, name);
+            params.extended_key_usages = vec![purpose];
+            let key = KeyPair::generate().unwrap();
+            let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+            fs::write(dir.join(format!("{}.pem", name)), cert.pem()).unwrap();
+            fs::write(dir.join(format!("{}.key", name)), key.serialize_pem()).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn client_certificate() {
+        let dir = write_certs();
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let address: &'static str = Box::leak(format!("tls://localhost:{}", port).into_boxed_str());
+
+        let mut config = server::Config::default();
+        let ca = dir.join("ca.pem");
+        config.tls = Some(server_config(&dir.join("server.pem"), &dir.join("server.key"), Some(&ca)).unwrap());
+        let mut server = Server::with_config(Arc::new(Mutex::new(())), config);
+        server.on(ClientName::Any, MsgName::Is("whoami"), |msg, state, _| {
+            let state = state.lock().unwrap();
+            state.client(&msg.client).and_then(|c| c.peer.cert_subject.clone()).map(|s| s.into_bytes())
+        }).unwrap();
+        let state = server.state.clone();
+        thread::spawn(move || server.listen(address).unwrap());
+        thread::sleep(Duration::from_millis(100));
+
+        // Client with certificate
+        let mut config = client::Config::default();
+        let identity = (dir.join("client-a.pem"), dir.join("client-a.key"));
+        config.tls = Some(client_config(&ca, Some((&identity.0, &identity.1))).unwrap());
+        let mut client = Client::connect_with(address, (), Some("a"), config).unwrap();
+        let (ans_tx, ans_rx) = mpsc::channel();
+        client.req("whoami", None, ans_tx).unwrap();
+        let ans = ans_rx.recv_timeout(Duration::from_secs(5)).unwrap().unwrap();
+        assert_eq!(ans, Some(Vec::from("CN=client-a")));
+        assert_eq!(state.lock().unwrap().client("a").unwrap().peer.transport, Transport::Tls);
+
+        // Client without certificate
+        let mut config = client::Config::default();
+        config.tls = Some(client_config(&ca, None).unwrap());
+        assert!(Client::connect_with(address, (), Some("b"), config).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,155 @@
+use rand;
+use std::cell::Cell;
+use std::fmt;
+use tracing::Span;
+use utils;
+
+/// Length of encoded trace context.
+pub const TRACE_LEN: usize = 32;
+
+thread_local! {
+    static CURRENT: Cell<Option<TraceCtx>> = const { Cell::new(None) };
+}
+
+/// Trace context carried by messages to link them across processes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TraceCtx {
+    /// Id shared by all messages caused by the same initial one.
+    pub trace_id: u128,
+    /// Id of the operation that sent the message.
+    pub span_id: u64,
+    /// Id of the operation that caused this one.
+    pub parent_id: Option<u64>,
+}
+
+/// Restores previous trace context of the thread when dropped.
+pub struct TraceGuard {
+    prev: Option<TraceCtx>,
+}
+
+impl TraceCtx {
+    /// Start new trace.
+    pub fn new() -> Self {
+        TraceCtx {
+            trace_id: (u128::from(rand::random::<u64>()) << 64) | u128::from(rand::random::<u64>()),
+            span_id: span_id(),
+            parent_id: None,
+        }
+    }
+
+    /// Context of the operation caused by this one.
+    pub fn child(&self) -> Self {
+        TraceCtx {
+            trace_id: self.trace_id,
+            span_id: span_id(),
+            parent_id: Some(self.span_id),
+        }
+    }
+
+    /// Child of the current context of the thread or new trace.
+    pub fn next() -> Self {
+        match TraceCtx::current() {
+            Some(ctx) => ctx.child(),
+            None => TraceCtx::new(),
+        }
+    }
+
+    /// Trace context of the running handler.
+    pub fn current() -> Option<TraceCtx> {
+        CURRENT.with(|c| c.get())
+    }
+
+    /// Make this context current for the thread until the guard is dropped.
+    pub fn enter(self) -> TraceGuard {
+        TraceGuard {
+            prev: CURRENT.with(|c| c.replace(Some(self))),
+        }
+    }
+
+    /// Fill `trace_id`, `span_id` and `parent_id` fields of the tracing span.
+    pub fn record(&self, span: &Span) {
+        span.record("trace_id", format_args!("{:032x}", self.trace_id));
+        span.record("span_id", format_args!("{:016x}", self.span_id));
+        if let Some(parent_id) = self.parent_id {
+            span.record("parent_id", format_args!("{:016x}", parent_id));
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; TRACE_LEN] {
+        let mut out = [0u8; TRACE_LEN];
+        out[..16].copy_from_slice(&self.trace_id.to_be_bytes());
+        out[16..24].copy_from_slice(&self.span_id.to_be_bytes());
+        out[24..].copy_from_slice(&self.parent_id.unwrap_or(0).to_be_bytes());
+        out
+    }
+
+    pub fn from_bytes(bin: &[u8]) -> Self {
+        let parent_id = utils::bytes_to_u64(&bin[24..32]);
+        TraceCtx {
+            trace_id: u128::from(utils::bytes_to_u64(&bin[..8])) << 64
+                | u128::from(utils::bytes_to_u64(&bin[8..16])),
+            span_id: utils::bytes_to_u64(&bin[16..24]),
+            parent_id: if parent_id == 0 { None } else { Some(parent_id) },
+        }
+    }
+}
+
+impl Default for TraceCtx {
+    fn default() -> Self {
+        TraceCtx::new()
+    }
+}
+
+impl Drop for TraceGuard {
+    fn drop(&mut self) {
+        CURRENT.with(|c| c.set(self.prev));
+    }
+}
+
+/// W3C traceparent format.
+impl fmt::Display for TraceCtx {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "00-{:032x}-{:016x}-01", self.trace_id, self.span_id)
+    }
+}
+
+/// Random non-zero span id.
+fn span_id() -> u64 {
+    loop {
+        let id = rand::random::<u64>();
+        if id != 0 {
+            return id;
+        }
+    }
+}
+
+// -----------------------------
+// --- --- --- Tests --- --- ---
+// -----------------------------
+#[cfg(test)]
+mod tests {
+    use trace::*;
+
+    #[test]
+    fn encoding() {
+        let root = TraceCtx::new();
+        let child = root.child();
+        assert_eq!(child.trace_id, root.trace_id);
+        assert_eq!(child.parent_id, Some(root.span_id));
+        assert_eq!(TraceCtx::from_bytes(&child.to_bytes()), child);
+        assert_eq!(TraceCtx::from_bytes(&root.to_bytes()), root);
+    }
+
+    #[test]
+    fn current() {
+        assert_eq!(TraceCtx::current(), None);
+        let root = TraceCtx::new();
+        {
+            let _guard = root.enter();
+            assert_eq!(TraceCtx::current(), Some(root));
+            assert_eq!(TraceCtx::next().parent_id, Some(root.span_id));
+        }
+        assert_eq!(TraceCtx::current(), None);
+        assert_eq!(TraceCtx::next().parent_id, None);
+    }
+}
@@ -141,6 +141,16 @@ pub fn u64_to_bytes(num: u64) -> [u8; 8] {
     return out
 }
 
+/// Convert u32 to big-endian bytes.
+pub fn u32_to_bytes(num: u32) -> [u8; 4] {
+    num.to_be_bytes()
+}
+
+/// Convert 4 big-endian bytes to u32.
+pub fn bytes_to_u32(bin: &[u8]) -> u32 {
+    u32::from_be_bytes([bin[0], bin[1], bin[2], bin[3]])
+}
+
 /// Convert 8bytes to u64 (wait stabilization of u64.to_bytes()).
 pub fn bytes_to_u64(bin: &[u8]) -> u64 {
     let mut out: u64 = 0;
@@ -152,7 +162,69 @@ pub fn bytes_to_u64(bin: &[u8]) -> u64 {
     out += (bin[5] as u64) << 16;
     out += (bin[6] as u64) << 8;
     out += bin[7] as u64;
-    return out
+    out
+}
+
+/// Pack list of byte strings into one, each prefixed with its length.
+pub fn pack(parts: &[&[u8]]) -> Vec<u8> {
+    let len = parts.iter().map(|p| p.len() + 8).sum();
+    let mut out = Vec::with_capacity(len);
+    for part in parts {
+        out.extend_from_slice(&u64_to_bytes(part.len() as u64));
+        out.extend_from_slice(part);
+    }
+    out
+}
+
+/// Unpack byte strings packed with `pack()`, ignores malformed tail.
+pub fn unpack(bin: &[u8]) -> Vec<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+    while bin.len() >= pos + 8 {
+        let len = bytes_to_u64(&bin[pos..pos + 8]) as usize;
+        let start = pos + 8;
+        if bin.len() - start < len {
+            break;
+        }
+        out.push(Vec::from(&bin[start..start + len]));
+        pos = start + len;
+    }
+    out
+}
+
+/// Quote and escape string for JSON.
+pub fn json_str(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Optional string as JSON string or null.
+pub fn json_opt(s: Option<&str>) -> String {
+    match s {
+        Some(s) => json_str(s),
+        None => "null".to_string(),
+    }
+}
+
+/// Seconds since unix epoch.
+pub fn unix_secs(time: SystemTime) -> u64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(dur) => dur.as_secs(),
+        Err(_) => 0,
+    }
 }
 
 // -----------------------------
@@ -176,4 +248,17 @@ mod tests {
     fn bid_dif() {
         assert!(bid() != [0u8; 12] && bid() != bid());
     }
+
+    #[test]
+    fn pack_unpack() {
+        let packed = pack(&[b"id", b"", b"name"]);
+        assert_eq!(unpack(&packed), vec![Vec::from("id"), Vec::new(), Vec::from("name")]);
+        assert_eq!(unpack(&packed[..packed.len() - 1]).len(), 2);
+    }
+
+    #[test]
+    fn json_escaping() {
+        assert_eq!(json_str("a\"b\\c\n\u{1}"), "\"a\\\"b\\\\c\\n\\u0001\"");
+        assert_eq!(json_opt(None), "null");
+    }
 }
@@ -0,0 +1,382 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use errors::Error;
+use sha1::{Digest, Sha1};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Max duration of the upgrade request.
+static UPGRADE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Max size of the upgrade request.
+static MAX_REQUEST_LEN: usize = 8192;
+/// Appended to the client's key to get the accept key (RFC 6455).
+static ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// Frame opcodes
+static OP_CONTINUATION: u8 = 0x0;
+static OP_TEXT: u8 = 0x1;
+static OP_BINARY: u8 = 0x2;
+static OP_CLOSE: u8 = 0x8;
+static OP_PING: u8 = 0x9;
+static OP_PONG: u8 = 0xa;
+
+// Close codes
+static CLOSE_NORMAL: u16 = 1000;
+static CLOSE_PROTOCOL: u16 = 1002;
+static CLOSE_UNSUPPORTED: u16 = 1003;
+
+/// Server side of WebSocket connection, `con` frames are carried by binary messages.
+#[derive(Debug)]
+pub struct WsStream {
+    reader: TcpStream,
+    writer: Arc<Mutex<TcpStream>>,
+    /// Payload bytes left in the current frame.
+    remaining: u64,
+    mask: [u8; 4],
+    mask_pos: usize,
+}
+
+impl WsStream {
+    /// Answer the upgrade request, blocks until it's received.
+    /// Requests for other than `path` are refused.
+    pub fn accept(mut tcp: TcpStream, path: &str) -> Result<WsStream, Error> {
+        tcp.set_read_timeout(Some(UPGRADE_TIMEOUT))?;
+        let request = read_request(&mut tcp)?;
+        let ans = match upgrade(&request, path) {
+            Ok(ans) => ans,
+            Err((status, reason)) => {
+                let res = format!("HTTP/1.1 {}\r\nConnection: close\r\nContent-Length: 0\r\n\r\n", status);
+                tcp.write_all(res.as_bytes()).unwrap_or(());
+                return Err(Error::WebSocket(reason));
+            }
+        };
+        tcp.write_all(ans.as_bytes())?;
+        tcp.set_read_timeout(None)?;
+
+        Ok(WsStream {
+            reader: tcp.try_clone()?,
+            writer: Arc::new(Mutex::new(tcp)),
+            remaining: 0,
+            mask: [0; 4],
+            mask_pos: 0,
+        })
+    }
+
+    /// Clone for writing, only one of the clones should read.
+    pub fn try_clone(&self) -> io::Result<WsStream> {
+        Ok(WsStream {
+            reader: self.reader.try_clone()?,
+            writer: self.writer.clone(),
+            remaining: 0,
+            mask: [0; 4],
+            mask_pos: 0,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.reader.local_addr()
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.reader.peer_addr()
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.reader.set_nonblocking(nonblocking)
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.reader.shutdown(how)
+    }
+
+    /// Read frames until the data one, returns false if the connection is closed.
+    fn next_frame(&mut self) -> io::Result<bool> {
+        let mut head = [0u8; 2];
+        match self.reader.read_exact(&mut head) {
+            Ok(_) => (),
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(err) => return Err(err),
+        }
+        let opcode = head[0] & 0x0f;
+        let len = match head[1] & 0x7f {
+            126 => {
+                let mut ext = [0u8; 2];
+                self.reader.read_exact(&mut ext)?;
+                u64::from(u16::from_be_bytes(ext))
+            }
+            127 => {
+                let mut ext = [0u8; 8];
+                self.reader.read_exact(&mut ext)?;
+                u64::from_be_bytes(ext)
+            }
+            len => u64::from(len),
+        };
+
+        // Clients must mask frames, control frames are short
+        let is_control = opcode & 0x8 != 0;
+        if head[1] & 0x80 == 0 || (is_control && (len > 125 || head[0] & 0x80 == 0)) {
+            return self.fail(CLOSE_PROTOCOL, "Malformed WebSocket frame");
+        }
+        let mut mask = [0u8; 4];
+        self.reader.read_exact(&mut mask)?;
+
+        if opcode == OP_BINARY || opcode == OP_CONTINUATION {
+            self.remaining = len;
+            self.mask = mask;
+            self.mask_pos = 0;
+            return Ok(true);
+        }
+        if opcode == OP_TEXT {
+            // Unread data would reset connection before the close frame is received
+            io::copy(&mut (&self.reader).take(len), &mut io::sink())?;
+            return self.fail(CLOSE_UNSUPPORTED, "Text WebSocket messages are not supported");
+        }
+
+        let mut payload = vec![0u8; len as usize];
+        self.reader.read_exact(&mut payload)?;
+        for (i, b) in payload.iter_mut().enumerate() {
+            *b ^= mask[i % 4];
+        }
+        match opcode {
+            op if op == OP_PING => self.send(OP_PONG, &payload)?,
+            op if op == OP_PONG => (),
+            op if op == OP_CLOSE => {
+                self.send(OP_CLOSE, &CLOSE_NORMAL.to_be_bytes()).unwrap_or(());
+                return Ok(false);
+            }
+            _ => return self.fail(CLOSE_PROTOCOL, "Unknown WebSocket opcode"),
+        }
+        Ok(true)
+    }
+
+    /// Close connection because of the peer's mistake.
+    fn fail(&self, code: u16, reason: &str) -> io::Result<bool> {
+        self.send(OP_CLOSE, &code.to_be_bytes()).unwrap_or(());
+        Err(io::Error::new(io::ErrorKind::InvalidData, reason))
+    }
+
+    /// Write one unmasked frame.
+    fn send(&self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut head = Vec::with_capacity(10);
+        head.push(0x80 | opcode);
+        match payload.len() {
+            len if len < 126 => head.push(len as u8),
+            len if len <= 0xffff => {
+                head.push(126);
+                head.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                head.push(127);
+                head.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+
+        let mut tcp = self
+            .writer
+            .lock()
+            .map_err(|_| io::Error::other("WebSocket writer is poisoned"))?;
+        tcp.write_all(&head)?;
+        tcp.write_all(payload)
+    }
+}
+
+impl Read for WsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.remaining == 0 {
+            if !self.next_frame()? {
+                return Ok(0);
+            }
+        }
+
+        let len = (buf.len() as u64).min(self.remaining) as usize;
+        let n = self.reader.read(&mut buf[..len])?;
+        for b in buf[..n].iter_mut() {
+            *b ^= self.mask[self.mask_pos % 4];
+            self.mask_pos += 1;
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for WsStream {
+    /// Send the buffer as one binary message.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.send(OP_BINARY, buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.writer.lock() {
+            Ok(mut tcp) => tcp.flush(),
+            Err(_) => Err(io::Error::other("WebSocket writer is poisoned")),
+        }
+    }
+}
+
+/// Read HTTP request head.
+fn read_request(tcp: &mut TcpStream) -> Result<String, Error> {
+    // Byte by byte, so nothing after the head is consumed
+    let mut request = Vec::with_capacity(512);
+    let mut byte = [0u8; 1];
+    while !request.ends_with(b"\r\n\r\n") {
+        if request.len() >= MAX_REQUEST_LEN {
+            return Err(Error::WebSocket("Upgrade request is too long.".to_string()));
+        }
+        tcp.read_exact(&mut byte)?;
+        request.push(byte[0]);
+    }
+    Ok(String::from_utf8_lossy(&request).to_string())
+}
+
+/// Check upgrade request, returns the answer or error status and reason.
+fn upgrade(request: &str, path: &str) -> Result<String, (&'static str, String)> {
+    let mut lines = request.split("\r\n");
+    let mut start = lines.next().unwrap_or("").split(' ');
+    let (method, target) = (start.next().unwrap_or(""), start.next().unwrap_or(""));
+    if method != "GET" {
+        return Err(("405 Method Not Allowed", format!("Unexpected method {:?}", method)));
+    }
+    if target.split('?').next() != Some(path) {
+        return Err(("404 Not Found", format!("Unexpected path {:?}", target)));
+    }
+
+    let header = |name: &str| {
+        request.split("\r\n").skip(1).find_map(|line| {
+            let mut kv = line.splitn(2, ':');
+            match (kv.next(), kv.next()) {
+                (Some(key), Some(val)) if key.trim().eq_ignore_ascii_case(name) => Some(val.trim()),
+                _ => None,
+            }
+        })
+    };
+    let has_token = |name: &str, token: &str| {
+        header(name).is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+    };
+    if !has_token("upgrade", "websocket") || !has_token("connection", "upgrade") {
+        return Err(("400 Bad Request", "Not a WebSocket upgrade".to_string()));
+    }
+    if header("sec-websocket-version") != Some("13") {
+        return Err(("426 Upgrade Required\r\nSec-WebSocket-Version: 13", "Unsupported version".to_string()));
+    }
+    let key = match header("sec-websocket-key") {
+        Some(key) => key,
+        None => return Err(("400 Bad Request", "No WebSocket key".to_string())),
+    };
+
+    let mut ans = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n",
+        accept_key(key)
+    );
+    if has_token("sec-websocket-protocol", "con") {
+        ans.push_str("Sec-WebSocket-Protocol: con\r\n");
+    }
+    ans.push_str("\r\n");
+    Ok(ans)
+}
+
+/// Accept key for the client's key.
+fn accept_key(key: &str) -> String {
+    let mut sha = Sha1::new();
+    sha.update(key.as_bytes());
+    sha.update(ACCEPT_GUID.as_bytes());
+    BASE64.encode(sha.finalize())
+}
+
+// -----------------------------
+// --- --- --- Tests --- --- ---
+// -----------------------------
+#[cfg(test)]
+mod tests {
+    use message::{Msg, MsgName, MSG_REQ};
+    use peer::Transport;
+    use server::{ClientName, Server};
+    use std::net::TcpListener;
+    use std::thread;
+    use utils;
+    use ws::*;
+
+    /// Write masked frame as browser does.
+    fn send_frame(tcp: &mut TcpStream, head: u8, payload: &[u8]) {
+        let mask = [1u8, 2, 3, 4];
+        let mut frame = vec![head, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        tcp.write_all(&frame).unwrap();
+    }
+
+    /// Read unmasked short frame.
+    fn recv_frame(tcp: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut head = [0u8; 2];
+        tcp.read_exact(&mut head).unwrap();
+        assert_eq!(head[1] & 0x80, 0);
+        let mut payload = vec![0u8; (head[1] & 0x7f) as usize];
+        tcp.read_exact(&mut payload).unwrap();
+        (head[0], payload)
+    }
+
+    fn upgrade_request(path: &str) -> String {
+        format!(
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\
+             Sec-WebSocket-Protocol: con\r\n\r\n",
+            path
+        )
+    }
+
+    #[test]
+    fn accept_key_from_rfc() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn browser_client() {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let address: &'static str = Box::leak(format!("ws://127.0.0.1:{}/bus", port).into_boxed_str());
+
+        let mut server = Server::new(Arc::new(Mutex::new(())));
+        server.on(ClientName::Any, MsgName::Is("echo"), |msg, _, _| msg.body.clone()).unwrap();
+        let state = server.state.clone();
+        thread::spawn(move || server.listen(address).unwrap());
+        thread::sleep(Duration::from_millis(100));
+
+        // Wrong path
+        let mut tcp = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        tcp.write_all(upgrade_request("/").as_bytes()).unwrap();
+        let mut res = String::new();
+        tcp.read_to_string(&mut res).unwrap();
+        assert!(res.starts_with("HTTP/1.1 404"));
+
+        // Upgrade
+        let mut tcp = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        tcp.write_all(upgrade_request("/bus").as_bytes()).unwrap();
+        let res = read_request(&mut tcp).unwrap();
+        assert!(res.starts_with("HTTP/1.1 101"));
+        assert!(res.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        assert!(res.contains("Sec-WebSocket-Protocol: con\r\n"));
+
+        // Handshake
+        let body = utils::pack(&[b"ui", b""]);
+        send_frame(&mut tcp, 0x82, &Msg::new("", 1, MSG_REQ, "$handshake").with_body(&body).to_bytes());
+        let (head, frame) = recv_frame(&mut tcp);
+        assert_eq!(head, 0x82);
+        let ans = Msg::from_bytes(&frame, "").unwrap();
+        assert_eq!((ans.name.as_str(), ans.err), ("$handshake", false));
+        assert_eq!(state.lock().unwrap().client("ui").unwrap().peer.transport, Transport::WebSocket);
+
+        // Fragmented request with ping in the middle
+        let req = Msg::new("", 2, MSG_REQ, "echo").with_body(b"hi").to_bytes();
+        send_frame(&mut tcp, 0x02, &req[..5]);
+        send_frame(&mut tcp, 0x89, b"p");
+        send_frame(&mut tcp, 0x80, &req[5..]);
+        assert_eq!(recv_frame(&mut tcp), (0x8a, Vec::from("p")));
+        let ans = Msg::from_bytes(&recv_frame(&mut tcp).1, "").unwrap();
+        assert_eq!((ans.id, ans.body), (2, Some(Vec::from("hi"))));
+
+        // Text messages are refused
+        send_frame(&mut tcp, 0x81, b"{}");
+        assert_eq!(recv_frame(&mut tcp), (0x88, CLOSE_UNSUPPORTED.to_be_bytes().to_vec()));
+    }
+}